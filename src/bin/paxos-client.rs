@@ -0,0 +1,59 @@
+//! Send one request to a cluster and print the answer:
+//! `paxos-client CONFIG CLIENT_ID COMMAND`, where `COMMAND` is one of the console's
+//! requests, e.g. `put 1 key value`. It goes to the server it names first.
+
+use paxos::client::{Client, ClientConfig};
+use paxos::cluster::ClusterConfig;
+use paxos::console::{parse_request, send_request};
+use paxos::fault::FaultPolicy;
+use paxos::network::Broker;
+use std::process;
+use std::sync::Arc;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 4 {
+        eprintln!("usage: paxos-client CONFIG CLIENT_ID COMMAND");
+        process::exit(2);
+    }
+    let config = ClusterConfig::load(&args[1]).unwrap_or_else(|e| {
+        eprintln!("error: can't load {}: {}", args[1], e);
+        process::exit(1);
+    });
+    let clients: Vec<usize> = config.clients.iter().map(|node| node.id).collect();
+    let id = match args[2].parse::<usize>() {
+        Ok(id) if clients.contains(&id) => id,
+        _ => {
+            eprintln!("error: {} lists no client #{}", args[1], args[2]);
+            process::exit(1);
+        }
+    };
+    let (server, cmd) = match parse_request(&args[3..].join(" ")) {
+        Ok((id, cmd)) if config.is_server(id) => (id, cmd),
+        Ok((id, _)) => {
+            eprintln!("error: {} lists no server #{}", args[1], id);
+            process::exit(1);
+        }
+        Err(_) => {
+            eprintln!("error: unknown command.");
+            process::exit(2);
+        }
+    };
+
+    // Answers come back over connections the servers open to our own address.
+    let broker = Broker::new(id, config.addr_table(), Arc::new(FaultPolicy::default()));
+    let servers = config.servers.iter().map(|node| node.id).collect();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let answer = rt.block_on(async move {
+        let mut client = Client::new(broker, servers, ClientConfig::default());
+        client.prefer(server);
+        send_request(&mut client, cmd).await
+    });
+    match answer {
+        Ok(answer) => println!("{}", answer),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
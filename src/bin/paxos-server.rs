@@ -0,0 +1,64 @@
+//! Run one server of a cluster: `paxos-server CONFIG ID`.
+
+use futures::channel::mpsc;
+use paxos::cluster::ClusterConfig;
+use paxos::fault::FaultPolicy;
+use paxos::kv::{KvCommand, KvStore};
+use paxos::network::Broker;
+use paxos::paxos::{Entry, Paxos};
+use paxos::storage::FileStorage;
+use std::process;
+use std::sync::Arc;
+use std::time::Instant;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (path, id) = match args.as_slice() {
+        [_, path, id] => (path, id.parse::<usize>()),
+        _ => {
+            eprintln!("usage: paxos-server CONFIG ID");
+            process::exit(2);
+        }
+    };
+    let config = ClusterConfig::load(path).unwrap_or_else(|e| {
+        eprintln!("error: can't load {}: {}", path, e);
+        process::exit(1);
+    });
+    let id = match id {
+        Ok(id) if config.is_server(id) => id,
+        _ => {
+            eprintln!("error: {} lists no server #{}", path, args[2]);
+            process::exit(1);
+        }
+    };
+
+    let wal_path = config.wal_path(id);
+    let storage = std::fs::create_dir_all(&config.data_dir)
+        .and_then(|_| FileStorage::<Entry<KvCommand>>::open(&wal_path))
+        .unwrap_or_else(|e| {
+            eprintln!("error: can't open {}: {}", wal_path.display(), e);
+            process::exit(1);
+        });
+    let paxos = Paxos::with_config(
+        id,
+        config.members(),
+        storage,
+        KvStore::default(),
+        config.paxos_config(),
+        Instant::now(),
+    );
+    let broker = Broker::new(id, config.addr_table(), Arc::new(FaultPolicy::default()));
+    let (itx, irx) = mpsc::unbounded();
+    let (otx, orx) = mpsc::unbounded();
+
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        tokio::select! {
+            result = broker.run(itx, orx) => if let Err(e) = result {
+                eprintln!("error: server #{} fail to listen: {}", id, e);
+                process::exit(1);
+            },
+            _ = paxos.run(otx, irx) => {}
+        }
+    });
+}
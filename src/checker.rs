@@ -0,0 +1,466 @@
+//! An exhaustive model checker for a single instance of the protocol.
+//!
+//! Starting from two clients each asking a different server to propose a value,
+//! it explores every order in which the datagrams in flight can be delivered, every
+//! point at which a proposer can time out, and every point at which a candidate can
+//! run for leader, breadth-first. A leader skips phase 1, as it does for real.
+//! Datagrams may also never arrive: each state is checked on its own, so that's the
+//! same as dropping whatever is still in flight. That goes for the datagrams a
+//! server sends itself as well. Heartbeats are never delivered, so leases and
+//! followers don't come into it. The number of rounds, counting each `Prepare` and
+//! `Elect` ballot once, is bounded to keep the state space finite.
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::paxos::*;
+use crate::quorum::QuorumSystem;
+use crate::state_machine::StateMachine;
+use crate::storage::{MemStorage, Storage};
+
+const ACCEPTORS: usize = 3;
+/// Servers #1 and #2 propose; all three accept.
+const PROPOSERS: [(usize, u32); 2] = [(1, 1), (2, 2)];
+const CLIENT: usize = 0;
+/// Long enough for any timer of a round to expire, short of the election timeout.
+const TIMEOUT_STEP: Duration = Duration::from_secs(3600);
+/// Far longer than any number of `TIMEOUT_STEP`s the rounds allow.
+const ELECTION_TIMEOUT: Duration = Duration::from_secs(100 * 24 * 3600);
+
+#[derive(Debug, Clone, Default)]
+struct Nothing;
+
+impl StateMachine<u32> for Nothing {
+    type Output = ();
+    type Snapshot = ();
+
+    fn apply(&mut self, _: u32) {}
+    fn snapshot(&self) {}
+    fn restore(&mut self, _: ()) {}
+}
+
+type Server = Paxos<u32, MemStorage<Entry<u32>>, Nothing>;
+
+/// A datagram on its way to server #`dst`.
+#[derive(Debug, Clone)]
+struct InFlight {
+    dst: usize,
+    incoming: Incoming<u32, ()>,
+    /// What it's interned as, once it has been.
+    id: Option<usize>,
+}
+
+/// A state of the cluster: the servers, and the datagrams they have yet to receive.
+#[derive(Debug, Clone)]
+pub struct Model {
+    quorums: Arc<dyn QuorumSystem>,
+    servers: Vec<Server>,
+    /// What each server is interned as, until it handles something else.
+    ids: Vec<Option<usize>>,
+    clocks: Vec<Instant>,
+    in_flight: Vec<InFlight>,
+    proposed: Vec<Entry<u32>>,
+    /// Every proposal each acceptor has accepted so far, including those it has
+    /// since replaced with a higher-numbered one.
+    votes: Vec<(usize, AcceptedProposal<Entry<u32>>)>,
+    /// Every value a phase-2 quorum of acceptors accepted in the same round so far.
+    chosen: Vec<Entry<u32>>,
+    /// Every ballot a `Prepare` or an `Elect` was sent for so far.
+    rounds: BTreeSet<SequenceNumber>,
+}
+
+/// Everything about a state but the datagrams in flight, with the servers and
+/// votes interned.
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct Key {
+    servers: Vec<usize>,
+    clocks: Vec<Instant>,
+    votes: Vec<usize>,
+}
+
+/// Numbers each distinct value by what it looks like, so that states compare
+/// exactly while every server state is kept in memory only once. Not everything
+/// in a server implements `Eq`, but all of it implements `Debug`.
+#[derive(Default)]
+struct Interner(HashMap<String, usize>);
+
+/// How the model gets from one state to the next.
+#[derive(Debug, Clone)]
+enum Step {
+    Deliver(usize, Incoming<u32, ()>),
+    TimeOut(usize),
+    RunForLeader(usize),
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::Deliver(dst, incoming) => {
+                write!(f, "#{} -> #{}: {:?}", incoming.src, dst, incoming.dgram)
+            }
+            Step::TimeOut(id) => write!(f, "#{} times out", id),
+            Step::RunForLeader(id) => write!(f, "#{} runs for leader", id),
+        }
+    }
+}
+
+/// A shortest sequence of steps leading to a state that breaks an invariant.
+#[derive(Debug)]
+pub struct Counterexample {
+    pub trace: Vec<String>,
+    pub violation: String,
+}
+
+impl fmt::Display for Counterexample {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, step) in self.trace.iter().enumerate() {
+            writeln!(f, "{:>3}. {}", i + 1, step)?;
+        }
+        write!(f, "violation: {}", self.violation)
+    }
+}
+
+impl Model {
+    fn new(quorums: Arc<dyn QuorumSystem>) -> Self {
+        let now = Instant::now();
+        let config = PaxosConfig {
+            // Elections only start when the checker says so.
+            election_timeout: ELECTION_TIMEOUT,
+            max_attempts: usize::MAX,
+            seed: Some(0),
+            quorums: quorums.clone(),
+            ..PaxosConfig::default()
+        };
+        let peers_id: HashSet<usize> = (1..=ACCEPTORS).collect();
+        let servers = (1..=ACCEPTORS)
+            .map(|id| {
+                let storage = MemStorage::new();
+                Paxos::with_config(id, peers_id.clone(), storage, Nothing, config.clone(), now)
+            })
+            .collect();
+        let calls = (0..).map(|seq| RequestId {
+            client: CLIENT,
+            seq,
+        });
+        let proposals: Vec<(usize, RequestId, u32)> = PROPOSERS
+            .iter()
+            .zip(calls)
+            .map(|(&(id, value), call)| (id, call, value))
+            .collect();
+        let in_flight = proposals
+            .iter()
+            .map(|&(id, call, value)| {
+                let dgram = Datagram::Request(Request::Propose { id: call, value });
+                InFlight::new(id, Incoming { src: CLIENT, dgram })
+            })
+            .collect();
+        Self {
+            quorums,
+            servers,
+            ids: vec![None; ACCEPTORS],
+            clocks: vec![now; ACCEPTORS],
+            in_flight,
+            proposed: proposals
+                .iter()
+                .map(|&(_, id, value)| Entry::Value { id, value })
+                .collect(),
+            votes: vec![],
+            chosen: vec![],
+            rounds: BTreeSet::new(),
+        }
+    }
+
+    fn server(&self, id: usize) -> &Server {
+        &self.servers[id - 1]
+    }
+
+    /// The states one step away, along with the step. Only `candidates` run for
+    /// leader.
+    fn successors(&self, candidates: &[usize]) -> Vec<(Step, Model)> {
+        let mut next = vec![];
+        for i in 0..self.in_flight.len() {
+            let mut model = self.clone();
+            let InFlight { dst, incoming, .. } = model.in_flight.remove(i);
+            let step = Step::Deliver(dst, incoming.clone());
+            let now = model.clocks[dst - 1];
+            let effects = model.servers[dst - 1].handle_incoming(incoming, now);
+            model.send(dst, effects);
+            next.push((step, model));
+        }
+        for id in 1..=ACCEPTORS {
+            // A leader's rounds time out into sending the same `Accept`s again,
+            // which adds nothing but copies of datagrams already in flight.
+            if self.server(id).is_leader() {
+                continue;
+            }
+            // The first tick abandons the round, the second starts the next one
+            // once the backoff is over.
+            if let Some(model) = self.tick(id, &[TIMEOUT_STEP, TIMEOUT_STEP]) {
+                next.push((Step::TimeOut(id), model));
+            }
+            if !candidates.contains(&id) {
+                continue;
+            }
+            if let Some(model) = self.tick(id, &[2 * ELECTION_TIMEOUT]) {
+                next.push((Step::RunForLeader(id), model));
+            }
+        }
+        next
+    }
+
+    /// The state after server #`id` ticks once per step of time, if it sent anything.
+    fn tick(&self, id: usize, steps: &[Duration]) -> Option<Model> {
+        let mut model = self.clone();
+        let mut sent = false;
+        for &step in steps {
+            model.clocks[id - 1] += step;
+            let effects = model.servers[id - 1].tick(model.clocks[id - 1]);
+            sent |= !effects.outgoing.is_empty();
+            model.send(id, effects);
+        }
+        if sent {
+            Some(model)
+        } else {
+            None
+        }
+    }
+
+    /// Put what server #`src` sent on the network, and update what's chosen.
+    fn send(&mut self, src: usize, effects: Effects<u32, ()>) {
+        self.ids[src - 1] = None;
+        for Outgoing { dst, dgram } in effects.outgoing {
+            if let Datagram::Request(Request::Prepare { seq, .. })
+            | Datagram::Request(Request::Elect { seq, .. }) = dgram
+            {
+                self.rounds.insert(seq);
+            }
+            if !is_modeled(&dgram) {
+                continue;
+            }
+            let mut dst: Vec<usize> = dst.into_iter().filter(|&id| id != CLIENT).collect();
+            dst.sort_unstable();
+            for id in dst {
+                let dgram = dgram.clone();
+                self.in_flight
+                    .push(InFlight::new(id, Incoming { src, dgram }));
+            }
+        }
+        if let Some(vote) = self.server(src).storage().accepted(0) {
+            if !self.votes.contains(&(src, vote.clone())) {
+                self.votes.push((src, vote));
+                self.update_chosen();
+            }
+        }
+    }
+
+    /// Add any value a phase-2 quorum has now voted for to those chosen.
+    fn update_chosen(&mut self) {
+        let members: BTreeSet<usize> = (1..=ACCEPTORS).collect();
+        for (_, proposal) in self.votes.iter() {
+            let voters: BTreeSet<usize> = self
+                .votes
+                .iter()
+                .filter(|(_, p)| p == proposal)
+                .map(|&(id, _)| id)
+                .collect();
+            if !self.quorums.is_phase2_quorum(&members, &voters) {
+                continue;
+            }
+            let value = std::iter::once(Entry::Noop)
+                .chain(self.proposed.iter().cloned())
+                .find(|value| *proposal == AcceptedProposal::new(proposal.seq(), value.clone()))
+                .expect("a value nobody proposed was accepted");
+            if !self.chosen.contains(&value) {
+                self.chosen.push(value);
+            }
+        }
+    }
+
+    /// Tells states apart, leaving out the datagrams in flight, which are interned
+    /// one by one and sorted, so that they can be compared as a multiset.
+    fn key(&mut self, interner: &mut Interner) -> (Key, Vec<usize>) {
+        for (server, id) in self.servers.iter().zip(self.ids.iter_mut()) {
+            if id.is_none() {
+                *id = Some(interner.intern(server));
+            }
+        }
+        let mut votes: Vec<usize> = self
+            .votes
+            .iter()
+            .map(|vote| interner.intern(vote))
+            .collect();
+        votes.sort_unstable();
+        let mut in_flight = vec![];
+        for sent in self.in_flight.iter_mut() {
+            if sent.id.is_none() {
+                sent.id = Some(interner.intern(&(sent.dst, &sent.incoming)));
+            }
+            in_flight.extend(sent.id);
+        }
+        in_flight.sort_unstable();
+        let key = Key {
+            servers: self.ids.iter().flatten().copied().collect(),
+            clocks: self.clocks.clone(),
+            votes,
+        };
+        (key, in_flight)
+    }
+}
+
+impl InFlight {
+    fn new(dst: usize, incoming: Incoming<u32, ()>) -> Self {
+        Self {
+            dst,
+            incoming,
+            id: None,
+        }
+    }
+}
+
+impl Interner {
+    fn intern(&mut self, value: &impl fmt::Debug) -> usize {
+        let next = self.0.len();
+        *self.0.entry(format!("{:?}", value)).or_insert(next)
+    }
+}
+
+/// Whether every datagram of `smaller` is also in `larger`, both sorted.
+fn includes(larger: &[usize], smaller: &[usize]) -> bool {
+    let mut larger = larger.iter();
+    smaller
+        .iter()
+        .all(|datagram| larger.any(|other| other == datagram))
+}
+
+/// Only instance 0 is modeled, along with the elections that cover it. A value that
+/// lost it moves on to instance 1, where it's left alone.
+fn is_modeled<V, O>(dgram: &Datagram<V, O>) -> bool {
+    match *dgram {
+        Datagram::Request(Request::Prepare { instance, .. })
+        | Datagram::Request(Request::Accept { instance, .. })
+        | Datagram::Request(Request::Learn { instance, .. })
+        | Datagram::Response(Response::Prepare { instance, .. })
+        | Datagram::Response(Response::PrepareRejected { instance, .. })
+        | Datagram::Response(Response::Accept { instance, .. })
+        | Datagram::Response(Response::AcceptRejected { instance, .. }) => instance == 0,
+        Datagram::Request(Request::Elect { .. })
+        | Datagram::Response(Response::Elect { .. })
+        | Datagram::Response(Response::ElectRejected { .. }) => true,
+        _ => false,
+    }
+}
+
+/// At most one value is chosen, and whatever a server learned was chosen after
+/// being proposed.
+pub fn safety(model: &Model) -> Result<(), String> {
+    if model.chosen.len() > 1 {
+        return Err(format!("{:?} are all chosen", model.chosen));
+    }
+    for id in 1..=ACCEPTORS {
+        if let Some(learned) = model.server(id).storage().chosen(0) {
+            let proposed = match learned {
+                Entry::Value { .. } => model.proposed.contains(&learned),
+                Entry::Noop | Entry::Batch(_) | Entry::Reconfigure(_) => false,
+            };
+            if !proposed {
+                return Err(format!("#{} learned {:?}, never proposed", id, learned));
+            }
+            if !model.chosen.contains(&learned) {
+                return Err(format!("#{} learned {:?}, never chosen", id, learned));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Explore every state reachable with `quorums` in up to `rounds` rounds, where only
+/// `candidates` run for leader. Returns how many distinct states there are, or a
+/// shortest way to break `invariant`.
+pub fn check(
+    quorums: Arc<dyn QuorumSystem>,
+    rounds: usize,
+    candidates: &[usize],
+    invariant: impl Fn(&Model) -> Result<(), String>,
+) -> Result<usize, Counterexample> {
+    let mut interner = Interner::default();
+    let mut initial = Model::new(quorums);
+    // The datagrams in flight in each state seen so far, by the rest of the state.
+    // Any of them may never arrive, so a state with fewer of them in flight than
+    // one already seen can't lead anywhere new.
+    let mut seen: HashMap<Key, Vec<Vec<usize>>> = HashMap::new();
+    let (key, in_flight) = initial.key(&mut interner);
+    seen.entry(key).or_default().push(in_flight);
+    // How each state was reached: its predecessor and the step from there.
+    let mut parents: Vec<Option<(usize, Step)>> = vec![None];
+    let mut queue = VecDeque::new();
+    queue.push_back((0, initial));
+    while let Some((index, model)) = queue.pop_front() {
+        if let Err(violation) = invariant(&model) {
+            let mut trace = vec![];
+            let mut index = index;
+            while let Some((parent, step)) = &parents[index] {
+                trace.push(step.to_string());
+                index = *parent;
+            }
+            trace.reverse();
+            return Err(Counterexample { trace, violation });
+        }
+        // Past the bound, a state is still checked but not explored further.
+        if model.rounds.len() > rounds {
+            continue;
+        }
+        for (step, mut next) in model.successors(candidates) {
+            let (key, in_flight) = next.key(&mut interner);
+            let seen = seen.entry(key).or_default();
+            if seen.iter().any(|other| includes(other, &in_flight)) {
+                continue;
+            }
+            seen.retain(|other| !includes(&in_flight, other));
+            seen.push(in_flight);
+            parents.push(Some((index, step)));
+            queue.push_back((parents.len() - 1, next));
+        }
+    }
+    Ok(parents.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::quorum::{Flexible, Majority};
+
+    #[test]
+    fn test_single_decree_is_safe() {
+        // Two rounds are enough for either proposer to retry with sequence number 2,
+        // or for #3 to win an election and take over whatever it learns of.
+        if let Err(counterexample) = check(Arc::new(Majority), 2, &[3], safety) {
+            panic!("counterexample:\n{}", counterexample);
+        }
+    }
+
+    #[test]
+    fn test_flexible_quorums_are_safe() {
+        // A single acceptor completes phase 2, and it takes all three for phase 1.
+        let quorums = Arc::new(Flexible { phase2: 1 });
+        if let Err(counterexample) = check(quorums, 2, &[3], safety) {
+            panic!("counterexample:\n{}", counterexample);
+        }
+    }
+
+    #[test]
+    fn test_counterexample_is_minimal() {
+        // Claim nothing is ever chosen: the shortest refutation takes one proposal,
+        // two promises and two acceptances.
+        let counterexample = check(Arc::new(Majority), 1, &[], |model| {
+            match model.chosen.first() {
+                Some(value) => Err(format!("{:?} is chosen", value)),
+                None => Ok(()),
+            }
+        })
+        .unwrap_err();
+        assert_eq!(counterexample.trace.len(), 7, "{}", counterexample);
+        assert!(counterexample.trace[0].contains("Propose"));
+    }
+}
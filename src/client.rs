@@ -0,0 +1,345 @@
+//! Talking to a cluster as a client: send a request to a server, wait for the
+//! answer, and try elsewhere when none comes.
+
+use futures::channel::mpsc;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::stream::StreamExt;
+use tokio::time;
+
+use crate::network::Broker;
+use crate::paxos::*;
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// How long to wait for an answer before asking another server.
+    pub timeout: Duration,
+    /// How many servers to ask, one after the other, before giving up.
+    pub max_attempts: usize,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(1),
+            max_attempts: 5,
+        }
+    }
+}
+
+/// A value the cluster chose for a client, and what applying it yielded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chosen<O> {
+    pub instance: usize,
+    pub output: O,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientError {
+    /// No server answered in time.
+    Timeout,
+    /// The client knows no server to ask.
+    NoServers,
+    /// The value may or may not have been chosen in `instance`, see `ProposeResult::Unknown`.
+    Unknown { instance: usize },
+    /// The server couldn't answer a query as consistently as asked.
+    QueryFailed,
+}
+
+impl ClientError {
+    /// Whether asking another server, or the same one again, may do better.
+    fn is_retryable(&self) -> bool {
+        !matches!(self, ClientError::Unknown { .. })
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientError::Timeout => write!(f, "no answer in time"),
+            ClientError::NoServers => write!(f, "no server to ask"),
+            ClientError::Unknown { instance } => {
+                write!(f, "may or may not be chosen at instance {}", instance)
+            }
+            ClientError::QueryFailed => write!(f, "can't read consistently"),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+fn chosen<O>(result: ProposeResult<O>) -> Result<Chosen<O>, ClientError> {
+    match result {
+        ProposeResult::Chosen { instance, output } => Ok(Chosen { instance, output }),
+        ProposeResult::Unknown { instance } => Err(ClientError::Unknown { instance }),
+    }
+}
+
+/// The client commands `entry` holds, in the order they're applied.
+fn commands<V>(entry: Entry<V>) -> Vec<V> {
+    match entry {
+        Entry::Value { value, .. } => vec![value],
+        Entry::Batch(commands) => commands.into_iter().map(|(_, value)| value).collect(),
+        Entry::Noop | Entry::Reconfigure(_) => vec![],
+    }
+}
+
+/// A client of the servers its `Broker` knows, one call at a time.
+///
+/// Requests go to the server that answered last, which is the leader once there is
+/// one: followers pass proposals and linearizable queries on to it, and it answers
+/// directly. When no answer comes in time, the client asks the next server. Retried
+/// proposals may be chosen more than once.
+///
+/// Each call has a `RequestId` of its own, which its retries share, and answers are
+/// matched to the call by it. The servers remember the last command of each client
+/// that took effect, so a retried proposal takes effect only once. Calls are numbered
+/// from the time the client was created, so that a restarted client doesn't look
+/// like the leftovers of the previous one.
+pub struct Client<V, O> {
+    id: usize,
+    // The `seq` of the next call's `RequestId`.
+    next_seq: u64,
+    servers: Vec<usize>,
+    // Where the next request goes, as an index into `servers`.
+    target: usize,
+    config: ClientConfig,
+    tx: Tx<Outgoing<V, O>>,
+    rx: Rx<Incoming<V, O>>,
+}
+
+impl<V: Value, O: Value> Client<V, O> {
+    /// A client that sends through `broker`, whose id and address are the client's,
+    /// to `servers`. Spawns the broker's tasks, so it must be called on a tokio runtime.
+    pub fn new(broker: Arc<Broker>, servers: Vec<usize>, config: ClientConfig) -> Self {
+        let id = broker.id();
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let (itx, irx) = mpsc::unbounded();
+        let (otx, orx) = mpsc::unbounded();
+        tokio::spawn(async move {
+            if let Err(e) = broker.run(itx, orx).await {
+                log!("Client #{} fail to listen: {}", id, e);
+            }
+        });
+        Self {
+            id,
+            next_seq: since_epoch.as_micros() as u64,
+            servers,
+            target: 0,
+            config,
+            tx: otx,
+            rx: irx,
+        }
+    }
+
+    /// Send the next request to server #`id`, which is added to the servers if needed.
+    pub fn prefer(&mut self, id: usize) {
+        self.target = match self.servers.iter().position(|&server| server == id) {
+            Some(target) => target,
+            None => {
+                self.servers.push(id);
+                self.servers.len() - 1
+            }
+        };
+    }
+
+    /// Have `value` chosen, and get what applying it yielded.
+    pub async fn propose(&mut self, value: V) -> Result<Chosen<O>, ClientError> {
+        let req = |id| Request::Propose {
+            id,
+            value: value.clone(),
+        };
+        self.call(req, |resp| match resp {
+            Response::Propose { result, .. } => Some(chosen(result.clone())),
+            _ => None,
+        })
+        .await
+    }
+
+    /// The commands chosen in `instance`, as up to date as `consistency` says, or
+    /// `None` if nothing is known to be chosen there yet. There may be several, for a
+    /// batch, or none, for a no-op or a change of acceptors.
+    pub async fn read(
+        &mut self,
+        instance: usize,
+        consistency: ReadConsistency,
+    ) -> Result<Option<Vec<V>>, ClientError> {
+        let req = |id| Request::Query {
+            id,
+            instance,
+            consistency,
+        };
+        self.call(req, |resp| match *resp {
+            Response::Query { ref val, .. } => Some(Ok(val.clone().map(commands))),
+            Response::QueryFailed { .. } => Some(Err(ClientError::QueryFailed)),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Have `members` become the acceptors, and get the instance that decided it.
+    pub async fn reconfigure(&mut self, members: BTreeSet<usize>) -> Result<usize, ClientError> {
+        let req = |id| Request::Reconfigure {
+            id,
+            members: members.clone(),
+        };
+        let chosen = self
+            .call(req, |resp| match resp {
+                Response::Reconfigure { result, .. } => Some(chosen(result.clone())),
+                _ => None,
+            })
+            .await?;
+        Ok(chosen.instance)
+    }
+
+    /// Who the server thinks is leading. Requests go there from now on.
+    pub async fn leader(&mut self) -> Result<Option<usize>, ClientError> {
+        let leader = self
+            .call(
+                |id| Request::Leader { id },
+                |resp| match *resp {
+                    Response::Leader { leader, .. } => Some(Ok(leader)),
+                    _ => None,
+                },
+            )
+            .await?;
+        if let Some(leader) = leader {
+            self.prefer(leader);
+        }
+        Ok(leader)
+    }
+
+    /// Send the request `req` makes for a new call until a server answers it for good,
+    /// or we run out of attempts. `answer` reads the result out of a response to the
+    /// call; late answers to earlier calls are dropped.
+    async fn call<T>(
+        &mut self,
+        req: impl FnOnce(RequestId) -> Request<V>,
+        answer: impl Fn(&Response<V, O>) -> Option<Result<T, ClientError>>,
+    ) -> Result<T, ClientError> {
+        let id = RequestId {
+            client: self.id,
+            seq: self.next_seq,
+        };
+        self.next_seq += 1;
+        let req = req(id);
+        if self.servers.is_empty() {
+            return Err(ClientError::NoServers);
+        }
+        let mut error = ClientError::Timeout;
+        for _ in 0..self.config.max_attempts.max(1) {
+            let server = self.servers[self.target % self.servers.len()];
+            let dgram = Datagram::Request(req.clone());
+            let dst = (server..server + 1).collect();
+            // The broker only goes away with us.
+            self.tx.unbounded_send(Outgoing { dst, dgram }).unwrap();
+
+            let deadline = time::Instant::now() + self.config.timeout;
+            error = ClientError::Timeout;
+            while let Ok(Some(Incoming { src, dgram })) =
+                time::timeout_at(deadline, self.rx.next()).await
+            {
+                let result = match dgram {
+                    Datagram::Response(ref resp) if resp.request_id() == Some(id) => answer(resp),
+                    _ => None,
+                };
+                match result {
+                    Some(Ok(t)) => {
+                        // Proposals are answered by the leader, so keep talking to it.
+                        self.prefer(src);
+                        return Ok(t);
+                    }
+                    Some(Err(e)) if e.is_retryable() => {
+                        error = e;
+                        break;
+                    }
+                    Some(Err(e)) => return Err(e),
+                    None => {}
+                }
+            }
+            log!(
+                "Client #{} got no answer from #{}: {}",
+                self.id,
+                server,
+                error
+            );
+            self.target = (self.target + 1) % self.servers.len();
+        }
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fault::FaultPolicy;
+    use crate::kv::{KvCommand, KvOutput, KvStore};
+    use crate::storage::MemStorage;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::time::Instant;
+
+    #[test]
+    fn test_retry_on_other_servers() {
+        let addr =
+            |id: usize| -> SocketAddr { format!("127.0.0.1:{}", 23400 + id).parse().unwrap() };
+        // #3 is listed but never started.
+        let addr_table: HashMap<usize, SocketAddr> = (0..4).map(|id| (id, addr(id))).collect();
+        let members: std::collections::HashSet<usize> = (1..4).collect();
+        let faults = Arc::new(FaultPolicy::default());
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let put = KvCommand::Put {
+            key: "k".to_string(),
+            value: "v".to_string(),
+        };
+        let value = put.clone();
+        let answers = rt.block_on(async move {
+            for id in 1..3 {
+                let (itx, irx) = mpsc::unbounded();
+                let (otx, orx) = mpsc::unbounded();
+                let paxos: Paxos<KvCommand, _, _> = Paxos::new(
+                    id,
+                    members.clone(),
+                    MemStorage::new(),
+                    KvStore::default(),
+                    Instant::now(),
+                );
+                let broker = Broker::new(id, addr_table.clone(), faults.clone());
+                tokio::spawn(async move {
+                    let _ = broker.run::<KvCommand, KvOutput>(itx, orx).await;
+                });
+                tokio::spawn(paxos.run(otx, irx));
+            }
+            let broker = Broker::new(0, addr_table, faults);
+            let config = ClientConfig {
+                timeout: Duration::from_secs(1),
+                max_attempts: 10,
+            };
+            let mut client = Client::<KvCommand, KvOutput>::new(broker, vec![3, 1, 2], config);
+            let chosen = client.propose(value).await;
+            let read = client.read(0, ReadConsistency::Quorum).await;
+            (chosen, read)
+        });
+        let (chosen, read) = answers;
+        assert_eq!(chosen.unwrap().instance, 0);
+        assert_eq!(read, Ok(Some(vec![put])));
+    }
+
+    #[test]
+    fn test_no_servers() {
+        let addr_table = (0..1).map(|id| (id, "127.0.0.1:23500".parse().unwrap()));
+        let faults = Arc::new(FaultPolicy::default());
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let read = rt.block_on(async move {
+            let broker = Broker::new(0, addr_table.collect(), faults);
+            let mut client =
+                Client::<KvCommand, KvOutput>::new(broker, vec![], ClientConfig::default());
+            client.read(0, ReadConsistency::Stale).await
+        });
+        assert_eq!(read, Err(ClientError::NoServers));
+    }
+}
@@ -0,0 +1,171 @@
+//! Where the nodes of a cluster run, for deployments with one process per node.
+//!
+//! ```toml
+//! quorums = "majority"
+//! data_dir = "/var/lib/paxos"
+//! members = [1]
+//!
+//! [[servers]]
+//! id = 1
+//! addr = "10.0.0.1:12345"
+//!
+//! [[servers]]
+//! id = 2
+//! addr = "10.0.0.2:12345"
+//!
+//! [[clients]]
+//! id = 0
+//! addr = "10.0.0.9:12345"
+//! ```
+
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::paxos::PaxosConfig;
+use crate::quorum::QuorumConfig;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Node {
+    pub id: usize,
+    pub addr: SocketAddr,
+}
+
+/// Every node of a cluster must be given the same.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ClusterConfig {
+    /// Every server that may run, whether it's an acceptor or a spare to reconfigure in later.
+    pub servers: Vec<Node>,
+    /// The acceptors the cluster starts with. Every server if not set.
+    #[serde(default)]
+    pub members: Option<BTreeSet<usize>>,
+    /// Who may send requests. Servers answer a client by connecting to its address
+    /// here, so it must be fixed and reachable from every server.
+    #[serde(default)]
+    pub clients: Vec<Node>,
+    #[serde(default)]
+    pub quorums: QuorumConfig,
+    /// Where the servers keep their write-ahead logs. The working directory if not set.
+    #[serde(default)]
+    pub data_dir: PathBuf,
+}
+
+impl ClusterConfig {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(s: &str) -> io::Result<Self> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        let config: Self = toml::from_str(s).map_err(|e| invalid(e.to_string()))?;
+        if config.servers.is_empty() {
+            return Err(invalid("no servers".to_string()));
+        }
+        let nodes = config.servers.iter().chain(&config.clients);
+        let mut ids = HashSet::new();
+        if let Some(node) = nodes.clone().find(|node| !ids.insert(node.id)) {
+            return Err(invalid(format!("#{} is listed twice", node.id)));
+        }
+        if let Some(members) = &config.members {
+            if members.is_empty() {
+                return Err(invalid("no members".to_string()));
+            }
+            if let Some(id) = members.iter().find(|&&id| !config.is_server(id)) {
+                return Err(invalid(format!("member #{} isn't a server", id)));
+            }
+        }
+        Ok(config)
+    }
+
+    /// The address of every node, as `Broker::new` expects it.
+    pub fn addr_table(&self) -> HashMap<usize, SocketAddr> {
+        let nodes = self.servers.iter().chain(&self.clients);
+        nodes.map(|node| (node.id, node.addr)).collect()
+    }
+
+    /// Whether `id` is one of the servers, member or spare.
+    pub fn is_server(&self, id: usize) -> bool {
+        self.servers.iter().any(|node| node.id == id)
+    }
+
+    /// The acceptors the cluster starts with, which every server is given.
+    pub fn members(&self) -> HashSet<usize> {
+        match &self.members {
+            Some(members) => members.iter().copied().collect(),
+            None => self.servers.iter().map(|node| node.id).collect(),
+        }
+    }
+
+    pub fn wal_path(&self, id: usize) -> PathBuf {
+        self.data_dir.join(format!("server-{}.wal", id))
+    }
+
+    pub fn paxos_config(&self) -> PaxosConfig {
+        PaxosConfig {
+            quorums: self.quorums.build(),
+            ..PaxosConfig::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = ClusterConfig::parse(
+            r#"
+            quorums = "flexible=2"
+
+            [[servers]]
+            id = 1
+            addr = "127.0.0.1:12346"
+
+            [[servers]]
+            id = 2
+            addr = "127.0.0.1:12347"
+
+            [[clients]]
+            id = 0
+            addr = "127.0.0.1:12345"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.quorums, QuorumConfig::Flexible { phase2: 2 });
+        assert_eq!(config.members(), (1..3).collect());
+        assert_eq!(config.addr_table().len(), 3);
+        assert_eq!(config.wal_path(2), PathBuf::from("server-2.wal"));
+
+        let duplicate = r#"
+            [[servers]]
+            id = 1
+            addr = "127.0.0.1:12346"
+
+            [[clients]]
+            id = 1
+            addr = "127.0.0.1:12345"
+        "#;
+        assert!(ClusterConfig::parse(duplicate).is_err());
+
+        let spare = r#"
+            members = [1]
+
+            [[servers]]
+            id = 1
+            addr = "127.0.0.1:12346"
+
+            [[servers]]
+            id = 2
+            addr = "127.0.0.1:12347"
+        "#;
+        let config = ClusterConfig::parse(spare).unwrap();
+        assert_eq!(config.members(), (1..2).collect());
+        assert!(config.is_server(2));
+        let stranger = spare.replace("[1]", "[1, 3]");
+        assert!(ClusterConfig::parse(&stranger).is_err());
+        assert!(ClusterConfig::parse("quorums = \"grid\"\nservers = []").is_err());
+    }
+}
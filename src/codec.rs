@@ -0,0 +1,159 @@
+use bincode::Options;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use tokio::prelude::*;
+
+use crate::paxos::{Datagram, Value};
+
+/// Frames larger than this are refused unless configured otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1 << 20;
+
+const HEADER_LEN: usize = 2 * std::mem::size_of::<u64>();
+
+/// Why a frame couldn't be read off a connection. Any of these leaves the
+/// stream in an unknown state, so the connection should be closed.
+#[derive(Debug)]
+pub enum DecodeError {
+    Io(io::Error),
+    FrameTooLarge { len: u64, max: usize },
+    Malformed(bincode::Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Io(e) => write!(f, "io error: {}", e),
+            DecodeError::FrameTooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds the limit of {}", len, max)
+            }
+            DecodeError::Malformed(e) => write!(f, "malformed datagram: {}", e),
+        }
+    }
+}
+
+impl Error for DecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DecodeError::Io(e) => Some(e),
+            DecodeError::FrameTooLarge { .. } => None,
+            DecodeError::Malformed(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(e: io::Error) -> Self {
+        DecodeError::Io(e)
+    }
+}
+
+impl DecodeError {
+    /// Whether the peer closed the connection, which is how connections normally end.
+    pub fn is_eof(&self) -> bool {
+        matches!(self, DecodeError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof)
+    }
+}
+
+/// Reads the frames written by `Datagram::encode_with_src`: the sender's id and the
+/// payload length as big-endian `u64`s, followed by the bincode-encoded datagram.
+#[derive(Debug, Clone, Copy)]
+pub struct Codec {
+    max_frame_size: usize,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl Codec {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self { max_frame_size }
+    }
+
+    /// Whether a frame from `encode_with_src` is small enough for peers to accept.
+    pub fn fits(&self, frame: &[u8]) -> bool {
+        frame.len() <= HEADER_LEN + self.max_frame_size
+    }
+
+    pub async fn read_frame<V: Value, O: Value, R: AsyncRead + Unpin>(
+        &self,
+        reader: &mut R,
+    ) -> Result<(usize, Datagram<V, O>), DecodeError> {
+        let src = reader.read_u64().await? as usize;
+        let len = reader.read_u64().await?;
+        if len > self.max_frame_size as u64 {
+            return Err(DecodeError::FrameTooLarge {
+                len,
+                max: self.max_frame_size,
+            });
+        }
+        let mut buf = vec![0u8; len as usize];
+        reader.read_exact(&mut buf).await?;
+        // The limit keeps a bogus length prefix inside the payload from
+        // making us allocate more than the frame itself.
+        let dgram = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_limit(len)
+            .deserialize(&buf)
+            .map_err(DecodeError::Malformed)?;
+        Ok((src, dgram))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::paxos::{Request, RequestId};
+
+    #[tokio::test]
+    async fn test_large_frame() {
+        let value = vec![7u8; 4096];
+        let id = RequestId { client: 3, seq: 1 };
+        let dgram = Datagram::<_, ()>::Request(Request::Propose {
+            id,
+            value: value.clone(),
+        });
+        let buf = dgram.encode_with_src(3);
+        let (src, decoded) = Codec::default()
+            .read_frame::<Vec<u8>, (), _>(&mut &buf[..])
+            .await
+            .unwrap();
+        assert_eq!(src, 3);
+        match decoded {
+            Datagram::Request(Request::Propose { id: i, value: v }) => {
+                assert_eq!(i, id);
+                assert_eq!(v, value);
+            }
+            _ => panic!("unexpected datagram {:?}", decoded),
+        }
+
+        let err = Codec::new(1024)
+            .read_frame::<Vec<u8>, (), _>(&mut &buf[..])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DecodeError::FrameTooLarge { max: 1024, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_bad_frames() {
+        let mut frame = vec![];
+        frame.extend_from_slice(&1u64.to_be_bytes());
+        frame.extend_from_slice(&4u64.to_be_bytes());
+        frame.extend_from_slice(&[0xff; 4]);
+        let err = Codec::default()
+            .read_frame::<u32, (), _>(&mut &frame[..])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DecodeError::Malformed(_)));
+
+        // Truncated in the middle of the payload.
+        let err = Codec::default()
+            .read_frame::<u32, (), _>(&mut &frame[..18])
+            .await
+            .unwrap_err();
+        assert!(err.is_eof());
+    }
+}
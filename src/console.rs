@@ -1,14 +1,20 @@
 use futures::channel::mpsc;
-use std::collections::HashMap;
+use futures::future::{self, AbortHandle, Future};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::io::BufRead;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
-use tokio::net::TcpStream;
-use tokio::prelude::*;
+use std::time::Duration;
 
+use crate::client::{Chosen, Client, ClientConfig};
+use crate::fault::{FaultPolicy, LinkFaults};
+use crate::kv::{KvCommand, KvOutput, KvStore};
 use crate::network::*;
 use crate::paxos::*;
+use crate::quorum::QuorumConfig;
+use crate::storage::FileStorage;
 
 macro_rules! print_flushed {
     ($($tokens: tt)*) => {
@@ -34,20 +40,119 @@ macro_rules! println_flushed {
     }
 }
 
+/// A line typed into the console.
 #[derive(Debug, PartialEq)]
-enum Command {
-    Start(usize),
-    Propose(usize, ValueType),
-    Query(usize),
+pub enum Command {
+    /// Launch the given number of servers, counting votes with the given quorums.
+    Start(usize, QuorumConfig),
+    /// Send a command to the key-value store through the given server.
+    Kv(usize, KvCommand),
+    /// Ask a server for the value chosen in an instance.
+    Query(usize, usize, ReadConsistency),
+    Leader(usize),
+    /// Set the faults of the links from a server to another, `None` meaning any.
+    Fault(Option<usize>, Option<usize>, LinkFaults),
+    ClearFaults,
+    /// Crash a server, stopping its tasks.
+    Kill(usize),
+    /// Bring back a killed server from its write-ahead log.
+    Restart(usize),
+    /// Split the servers into groups that can't talk to each other.
+    Partition(Vec<Vec<usize>>),
+    Isolate(usize),
+    Heal,
+    /// Ask a server to make the given servers the acceptors.
+    Reconfigure(usize, BTreeSet<usize>),
     Exit,
 }
 
 #[derive(Debug, PartialEq)]
-struct ParseCommandError;
+pub struct ParseCommandError;
 
-impl From<std::option::NoneError> for ParseCommandError {
-    fn from(_: std::option::NoneError) -> Self {
-        ParseCommandError
+/// Values may contain spaces, so they take up the rest of the line.
+fn rest_of_line<'a>(tokens: impl Iterator<Item = &'a str>) -> Result<String, ParseCommandError> {
+    let value = tokens.collect::<Vec<_>>().join(" ");
+    if value.is_empty() {
+        Err(ParseCommandError)
+    } else {
+        Ok(value)
+    }
+}
+
+/// A number, such as a server id.
+fn number(token: Option<&str>) -> Result<usize, ParseCommandError> {
+    token
+        .ok_or(ParseCommandError)?
+        .parse()
+        .map_err(|_| ParseCommandError)
+}
+
+/// A server id, or `*` for any.
+fn link_end(token: &str) -> Result<Option<usize>, ParseCommandError> {
+    match token {
+        "*" => Ok(None),
+        id => id.parse().map(Some).map_err(|_| ParseCommandError),
+    }
+}
+
+/// Faults given as `drop=P`, `dup=P`, `reorder=P` and `delay=MS` or `delay=MIN-MAX`
+/// (in milliseconds). What isn't given doesn't happen.
+fn link_faults<'a>(tokens: impl Iterator<Item = &'a str>) -> Result<LinkFaults, ParseCommandError> {
+    let rate = |s: &str| match s.parse::<f64>() {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+        _ => Err(ParseCommandError),
+    };
+    let millis = |s: &str| {
+        s.parse()
+            .map(Duration::from_millis)
+            .map_err(|_| ParseCommandError)
+    };
+    let mut faults = LinkFaults::default();
+    for token in tokens {
+        let mut setting = token.splitn(2, '=');
+        let (key, value) = (
+            setting.next().ok_or(ParseCommandError)?,
+            setting.next().ok_or(ParseCommandError)?,
+        );
+        match key {
+            "drop" => faults.drop_rate = rate(value)?,
+            "dup" => faults.dup_rate = rate(value)?,
+            "reorder" => faults.reorder_rate = rate(value)?,
+            "delay" => {
+                let mut range = value.splitn(2, '-');
+                faults.min_delay = millis(range.next().ok_or(ParseCommandError)?)?;
+                faults.max_delay = match range.next() {
+                    Some(max) => millis(max)?,
+                    None => faults.min_delay,
+                };
+                if faults.max_delay < faults.min_delay {
+                    return Err(ParseCommandError);
+                }
+            }
+            _ => return Err(ParseCommandError),
+        }
+    }
+    Ok(faults)
+}
+
+/// Groups of server ids like `1,2 | 3,4,5`.
+fn groups<'a>(tokens: impl Iterator<Item = &'a str>) -> Result<Vec<Vec<usize>>, ParseCommandError> {
+    let groups = tokens
+        .collect::<Vec<_>>()
+        .join(" ")
+        .split('|')
+        .map(|group| {
+            group
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|id| !id.is_empty())
+                .map(|id| id.parse::<usize>().map_err(|_| ParseCommandError))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if groups.iter().any(Vec::is_empty) {
+        Err(ParseCommandError)
+    } else {
+        Ok(groups)
     }
 }
 
@@ -55,21 +160,100 @@ impl FromStr for Command {
     type Err = ParseCommandError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let lower = s.to_lowercase();
-        let mut tokens = lower.split_whitespace();
-        match tokens.next()? {
+        let mut tokens = s.split_whitespace();
+        match tokens
+            .next()
+            .ok_or(ParseCommandError)?
+            .to_lowercase()
+            .as_str()
+        {
             "s" | "start" => {
-                let num = tokens.next()?.parse::<usize>().unwrap();
-                Ok(Self::Start(num))
+                let num = number(tokens.next())?;
+                let quorums = match tokens.next() {
+                    Some(token) => token.parse().map_err(|_| ParseCommandError)?,
+                    None => QuorumConfig::default(),
+                };
+                Ok(Self::Start(num, quorums))
             }
-            "p" | "propose" => {
-                let id = tokens.next()?.parse::<usize>().unwrap();
-                let val = tokens.next()?.parse::<ValueType>().unwrap();
-                Ok(Self::Propose(id, val))
+            "p" | "put" => {
+                let id = number(tokens.next())?;
+                let key = tokens.next().ok_or(ParseCommandError)?.to_string();
+                let value = rest_of_line(tokens)?;
+                Ok(Self::Kv(id, KvCommand::Put { key, value }))
+            }
+            "g" | "get" => {
+                let id = number(tokens.next())?;
+                let key = tokens.next().ok_or(ParseCommandError)?.to_string();
+                Ok(Self::Kv(id, KvCommand::Get { key }))
+            }
+            "d" | "del" | "delete" => {
+                let id = number(tokens.next())?;
+                let key = tokens.next().ok_or(ParseCommandError)?.to_string();
+                Ok(Self::Kv(id, KvCommand::Delete { key }))
+            }
+            "c" | "cas" => {
+                let id = number(tokens.next())?;
+                let key = tokens.next().ok_or(ParseCommandError)?.to_string();
+                let expected = match tokens.next().ok_or(ParseCommandError)? {
+                    "-" => None,
+                    expected => Some(expected.to_string()),
+                };
+                let value = rest_of_line(tokens)?;
+                Ok(Self::Kv(
+                    id,
+                    KvCommand::Cas {
+                        key,
+                        expected,
+                        value,
+                    },
+                ))
             }
             "q" | "query" => {
-                let id = tokens.next()?.parse::<usize>().unwrap();
-                Ok(Self::Query(id))
+                let id = number(tokens.next())?;
+                let mut instance = 0;
+                let mut consistency = ReadConsistency::Stale;
+                for token in tokens {
+                    match token {
+                        "stale" => consistency = ReadConsistency::Stale,
+                        "lease" => consistency = ReadConsistency::Lease,
+                        "quorum" => consistency = ReadConsistency::Quorum,
+                        token => instance = token.parse().map_err(|_| ParseCommandError)?,
+                    }
+                }
+                Ok(Self::Query(id, instance, consistency))
+            }
+            "l" | "leader" => {
+                let id = number(tokens.next())?;
+                Ok(Self::Leader(id))
+            }
+            "f" | "fault" => match tokens.next().ok_or(ParseCommandError)? {
+                "clear" => Ok(Self::ClearFaults),
+                src => {
+                    let src = link_end(src)?;
+                    let dst = link_end(tokens.next().ok_or(ParseCommandError)?)?;
+                    Ok(Self::Fault(src, dst, link_faults(tokens)?))
+                }
+            },
+            "k" | "kill" => {
+                let id = number(tokens.next())?;
+                Ok(Self::Kill(id))
+            }
+            "r" | "restart" => {
+                let id = number(tokens.next())?;
+                Ok(Self::Restart(id))
+            }
+            "partition" => Ok(Self::Partition(groups(tokens)?)),
+            "i" | "isolate" => {
+                let id = number(tokens.next())?;
+                Ok(Self::Isolate(id))
+            }
+            "h" | "heal" => Ok(Self::Heal),
+            "rc" | "reconfigure" => {
+                let id = number(tokens.next())?;
+                match groups(tokens)?.as_slice() {
+                    [members] => Ok(Self::Reconfigure(id, members.iter().copied().collect())),
+                    _ => Err(ParseCommandError),
+                }
             }
             "x" | "exit" => Ok(Self::Exit),
             _ => Err(ParseCommandError),
@@ -77,9 +261,91 @@ impl FromStr for Command {
     }
 }
 
+impl Command {
+    /// The server this command sends a request to, if it's sent as is.
+    fn server(&self) -> Option<usize> {
+        match *self {
+            Self::Kv(id, _)
+            | Self::Query(id, _, _)
+            | Self::Leader(id)
+            | Self::Reconfigure(id, _) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+/// Parse one of the console's commands that send a request to a server, e.g.
+/// `put 1 key value`, into the server's id and the command.
+pub fn parse_request(line: &str) -> Result<(usize, Command), ParseCommandError> {
+    let cmd = line.parse::<Command>()?;
+    let server = cmd.server().ok_or(ParseCommandError)?;
+    Ok((server, cmd))
+}
+
+/// Make the request `cmd` stands for through `client`, and describe the answer, or
+/// why there's none.
+pub async fn send_request(
+    client: &mut Client<KvCommand, KvOutput>,
+    cmd: Command,
+) -> Result<String, String> {
+    match cmd {
+        Command::Kv(_, value) => match client.propose(value.clone()).await {
+            Ok(Chosen { instance, output }) => Ok(format!(
+                "`{:?}` is chosen at instance {}, output: {:?}.",
+                value, instance, output
+            )),
+            Err(e) => Err(format!("fail to propose `{:?}`: {}.", value, e)),
+        },
+        Command::Query(_, instance, consistency) => {
+            match client.read(instance, consistency).await {
+                Ok(Some(commands)) if commands.is_empty() => {
+                    Ok(format!("no command at instance {}.", instance))
+                }
+                Ok(Some(commands)) => Ok(format!("{:?} at instance {}.", commands, instance)),
+                Ok(None) => Ok(format!("instance {} not learn yet.", instance)),
+                Err(e) => Err(format!("can't read instance {}: {}.", instance, e)),
+            }
+        }
+        Command::Leader(_) => match client.leader().await {
+            Ok(Some(leader)) => Ok(format!("leader is #{}.", leader)),
+            Ok(None) => Ok("no leader known.".to_string()),
+            Err(e) => Err(format!("can't tell the leader: {}.", e)),
+        },
+        Command::Reconfigure(_, members) => match client.reconfigure(members.clone()).await {
+            Ok(instance) => Ok(format!(
+                "members {:?} are chosen at instance {}.",
+                members, instance
+            )),
+            Err(e) => Err(format!("fail to reconfigure to {:?}: {}.", members, e)),
+        },
+        cmd => Err(format!("`{:?}` isn't a client request.", cmd)),
+    }
+}
+
 pub struct Console {
     rt: tokio::runtime::Runtime,
-    addr_table: Option<Arc<HashMap<usize, SocketAddr>>>,
+    addr_table: Option<HashMap<usize, SocketAddr>>,
+    base_port: usize,
+    // The acceptors the cluster was started with.
+    genesis: HashSet<usize>,
+    quorums: QuorumConfig,
+    brokers: HashMap<usize, Arc<Broker>>,
+    faults: Arc<FaultPolicy>,
+    // Client #0, which sends the requests typed in.
+    client: Option<Client<KvCommand, KvOutput>>,
+    // Handles to stop the tasks of every running server.
+    running: HashMap<usize, Vec<AbortHandle>>,
+}
+
+/// Where the servers keep their write-ahead logs.
+fn data_dir() -> PathBuf {
+    std::env::temp_dir().join("paxos-rs")
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Console {
@@ -87,6 +353,13 @@ impl Console {
         Self {
             rt: tokio::runtime::Runtime::new().unwrap(),
             addr_table: None,
+            base_port: 0,
+            genesis: HashSet::new(),
+            quorums: QuorumConfig::default(),
+            brokers: HashMap::new(),
+            faults: Arc::new(FaultPolicy::default()),
+            client: None,
+            running: HashMap::new(),
         }
     }
 
@@ -98,9 +371,31 @@ impl Console {
             if let Ok(line) = line {
                 if let Ok(cmd) = line.parse::<Command>() {
                     match cmd {
-                        Command::Start(num) => self.start_servers(num, 12345),
-                        Command::Propose(server_id, val) => self.propose(server_id, val),
-                        Command::Query(server_id) => self.query(server_id),
+                        // The old servers would hold on to their ports and logs.
+                        Command::Start(..) if self.addr_table.is_some() => {
+                            println_flushed!("error: servers have already started.")
+                        }
+                        Command::Start(num, quorums) => {
+                            self.quorums = quorums;
+                            self.start_servers(num, 12345)
+                        }
+                        Command::Kv(server_id, _)
+                        | Command::Query(server_id, _, _)
+                        | Command::Leader(server_id) => self.send_request(server_id, cmd),
+                        Command::Fault(src, dst, faults) => self.faults.set(src, dst, faults),
+                        Command::ClearFaults => self.faults.clear(),
+                        Command::Kill(server_id) => self.kill(server_id),
+                        Command::Restart(server_id) => self.restart(server_id),
+                        Command::Partition(groups) => self.partition(groups),
+                        Command::Isolate(server_id) => {
+                            if self.is_server(server_id) {
+                                self.faults.isolate(server_id, &self.servers());
+                            }
+                        }
+                        Command::Heal => self.faults.heal(),
+                        Command::Reconfigure(server_id, members) => {
+                            self.reconfigure(server_id, members)
+                        }
                         Command::Exit => break,
                     }
                 } else {
@@ -114,65 +409,156 @@ impl Console {
         }
     }
 
-    fn query(&mut self, server_id: usize) {
-        if let Some(peers_addr) = &self.addr_table {
-            if let Some(addr) = peers_addr.get(&server_id) {
-                let addr = addr.clone();
-                let task = async move {
-                    if let Ok(mut stream) = TcpStream::connect(addr).await {
-                        let dgram = Datagram::Request(Request::Query);
-                        stream.write_all(&dgram.encode_with_src(0)).await.unwrap();
-                    }
-                };
-                self.rt.block_on(task);
-            } else {
-                println_flushed!("error: server id dosen't exist.");
+    /// Send the request `cmd` stands for to `server_id` as client #0, and wait for
+    /// the answer, which may come from another server.
+    fn send_request(&mut self, server_id: usize, cmd: Command) {
+        if !self.is_server(server_id) {
+            return;
+        }
+        let client = self.client.as_mut().unwrap();
+        client.prefer(server_id);
+        match self.rt.block_on(send_request(client, cmd)) {
+            Ok(answer) => println_flushed!("Answer: {}", answer),
+            Err(e) => println_flushed!("error: {}", e),
+        }
+    }
+
+    fn start_servers(&mut self, server_num: usize, base_port: usize) {
+        let server_num = server_num + 1; // #0 for client.
+        self.base_port = base_port;
+        self.addr_table = Some((0..server_num).map(|id| (id, self.addr_of(id))).collect());
+        // skip client #0
+        self.genesis = (1..server_num).collect();
+        // Drop the state left by the servers of a previous console.
+        let _ = std::fs::remove_dir_all(data_dir());
+        std::fs::create_dir_all(data_dir()).unwrap();
+        (1..server_num).for_each(|id| {
+            self.spawn_server(id);
+        });
+        let broker = Broker::new(0, self.addr_table.clone().unwrap(), self.faults.clone());
+        self.brokers.insert(0, broker.clone());
+        let servers = self.servers();
+        let client = self
+            .rt
+            .enter(|| Client::new(broker, servers, ClientConfig::default()));
+        self.client = Some(client);
+    }
+
+    fn addr_of(&self, id: usize) -> SocketAddr {
+        format!("127.0.0.1:{}", self.base_port + id)
+            .parse()
+            .unwrap()
+    }
+
+    /// Launch the tasks of server #`id`, which picks up whatever its write-ahead
+    /// log holds.
+    fn spawn_server(&mut self, id: usize) {
+        let addr_table = self.addr_table.clone().unwrap();
+        let (itx, irx) = mpsc::unbounded();
+        let (otx, orx) = mpsc::unbounded();
+        let wal_path = data_dir().join(format!("server-{}.wal", id));
+        let storage = FileStorage::<Entry<KvCommand>>::open(wal_path).unwrap();
+        let peers_id = self.genesis.clone();
+        let now = std::time::Instant::now();
+        let config = PaxosConfig {
+            quorums: self.quorums.build(),
+            ..PaxosConfig::default()
+        };
+        let paxos = Paxos::with_config(id, peers_id, storage, KvStore::default(), config, now);
+        let broker = Broker::new(id, addr_table, self.faults.clone());
+        self.brokers.insert(id, broker.clone());
+        let serve = async move {
+            if let Err(e) = broker.run(itx, orx).await {
+                log!("Server #{} fail to listen: {}", id, e);
             }
-        } else {
+        };
+        let tasks = vec![
+            self.spawn_abortable(serve),
+            self.spawn_abortable(paxos.run(otx, irx)),
+        ];
+        self.running.insert(id, tasks);
+    }
+
+    fn spawn_abortable(&self, task: impl Future<Output = ()> + Send + 'static) -> AbortHandle {
+        let (task, handle) = future::abortable(task);
+        self.rt.spawn(task);
+        handle
+    }
+
+    /// The ids of the servers, the client excluded.
+    fn servers(&self) -> Vec<usize> {
+        let mut servers: Vec<usize> = self
+            .addr_table
+            .iter()
+            .flat_map(|addr_table| addr_table.keys().copied())
+            .filter(|&id| id != 0)
+            .collect();
+        servers.sort_unstable();
+        servers
+    }
+
+    fn is_server(&self, id: usize) -> bool {
+        if self.addr_table.is_none() {
             println_flushed!("error: servers haven't started.");
+            false
+        } else if !self.servers().contains(&id) {
+            println_flushed!("error: server id dosen't exist.");
+            false
+        } else {
+            true
         }
     }
 
-    fn propose(&mut self, server_id: usize, val: ValueType) {
-        if let Some(addr_table) = &self.addr_table {
-            if let Some(addr) = addr_table.get(&server_id) {
-                let addr = addr.clone();
-                let task = async move {
-                    if let Ok(mut stream) = TcpStream::connect(addr).await {
-                        let dgram = Datagram::Request(Request::Propose { value: val });
-                        stream.write_all(&dgram.encode_with_src(0)).await.unwrap();
-                    }
-                };
-                self.rt.block_on(task);
-            } else {
-                println_flushed!("error: server id dosen't exist.");
-            }
+    fn kill(&mut self, server_id: usize) {
+        if !self.is_server(server_id) {
+            return;
+        }
+        match self.running.remove(&server_id) {
+            Some(tasks) => tasks.iter().for_each(AbortHandle::abort),
+            None => println_flushed!("error: server #{} isn't running.", server_id),
+        }
+    }
+
+    fn restart(&mut self, server_id: usize) {
+        if !self.is_server(server_id) {
+            return;
+        }
+        if self.running.contains_key(&server_id) {
+            println_flushed!("error: server #{} is still running.", server_id);
         } else {
-            println_flushed!("error: servers haven't started.");
+            self.spawn_server(server_id);
         }
     }
 
-    fn start_servers(&mut self, server_num: usize, base_port: usize) {
-        let server_num = server_num + 1; // #0 for client.
-        let addr_table: Arc<HashMap<usize, SocketAddr>> = Arc::new(
-            ((base_port)..(base_port + server_num))
-                .enumerate()
-                .map(|(id, port)| (id, format!("127.0.0.1:{}", port).parse().unwrap()))
-                .collect(),
-        );
-        let start_server = |id: usize| {
-            let (itx, irx) = mpsc::unbounded();
-            let (otx, orx) = mpsc::unbounded();
-            // skip client #0
-            let paxos = Paxos::new(id, (1..server_num).collect(), otx, irx);
-            let broker = Broker::new(id, (*addr_table).clone());
-            self.rt.spawn(broker.run(itx, orx));
-            self.rt.spawn(paxos.run());
-        };
-        (0..server_num).for_each(|id| {
-            start_server(id);
-        });
-        self.addr_table = Some(addr_table.clone());
+    /// Launch the servers among `members` that don't exist yet, then ask server
+    /// #`server_id` to make `members` the acceptors.
+    fn reconfigure(&mut self, server_id: usize, members: BTreeSet<usize>) {
+        if !self.is_server(server_id) {
+            return;
+        }
+        if members.contains(&0) {
+            println_flushed!("error: #0 is the client.");
+            return;
+        }
+        for &id in members.iter() {
+            if self.addr_table.as_ref().unwrap().contains_key(&id) {
+                continue;
+            }
+            let addr = self.addr_of(id);
+            self.addr_table.as_mut().unwrap().insert(id, addr);
+            for broker in self.brokers.values() {
+                broker.add_peer(id, addr);
+            }
+            self.spawn_server(id);
+        }
+        self.send_request(server_id, Command::Reconfigure(server_id, members));
+    }
+
+    fn partition(&mut self, groups: Vec<Vec<usize>>) {
+        let ids = groups.iter().flatten().copied();
+        if ids.clone().all(|id| self.is_server(id)) {
+            self.faults.partition(&groups, &self.servers());
+        }
     }
 }
 
@@ -183,9 +569,125 @@ mod test {
     #[test]
     fn test_command_parse() {
         let start = "start 4";
-        assert_eq!(start.parse::<Command>(), Ok(Command::Start(4)));
-        let propose = "propose 0 42";
-        assert_eq!(propose.parse::<Command>(), Ok(Command::Propose(0, 42)));
+        assert_eq!(
+            start.parse::<Command>(),
+            Ok(Command::Start(4, QuorumConfig::Majority))
+        );
+        let start = "s 5 flexible=2";
+        let quorums = QuorumConfig::Flexible { phase2: 2 };
+        assert_eq!(start.parse::<Command>(), Ok(Command::Start(5, quorums)));
+        let start = "s 4 weights=1:2,3:0";
+        let weights = vec![(1, 2), (3, 0)].into_iter().collect();
+        let quorums = QuorumConfig::Weighted { weights };
+        assert_eq!(start.parse::<Command>(), Ok(Command::Start(4, quorums)));
+        let start = "s 4 grid";
+        assert_eq!(start.parse::<Command>(), Err(ParseCommandError));
+        let put = "put 0 k 42";
+        let cmd = KvCommand::Put {
+            key: "k".to_string(),
+            value: "42".to_string(),
+        };
+        assert_eq!(put.parse::<Command>(), Ok(Command::Kv(0, cmd)));
+        let put = "P 1 greeting Hello World";
+        let cmd = KvCommand::Put {
+            key: "greeting".to_string(),
+            value: "Hello World".to_string(),
+        };
+        assert_eq!(put.parse::<Command>(), Ok(Command::Kv(1, cmd)));
+        let put = "p 1 k";
+        assert_eq!(put.parse::<Command>(), Err(ParseCommandError));
+        let get = "get 2 k";
+        let cmd = KvCommand::Get {
+            key: "k".to_string(),
+        };
+        assert_eq!(get.parse::<Command>(), Ok(Command::Kv(2, cmd)));
+        let delete = "d 2 k";
+        let cmd = KvCommand::Delete {
+            key: "k".to_string(),
+        };
+        assert_eq!(delete.parse::<Command>(), Ok(Command::Kv(2, cmd)));
+        let cas = "cas 3 k old new value";
+        let cmd = KvCommand::Cas {
+            key: "k".to_string(),
+            expected: Some("old".to_string()),
+            value: "new value".to_string(),
+        };
+        assert_eq!(cas.parse::<Command>(), Ok(Command::Kv(3, cmd)));
+        let cas = "c 3 k - new";
+        let cmd = KvCommand::Cas {
+            key: "k".to_string(),
+            expected: None,
+            value: "new".to_string(),
+        };
+        assert_eq!(cas.parse::<Command>(), Ok(Command::Kv(3, cmd)));
+        let query = "query 1";
+        let stale = ReadConsistency::Stale;
+        assert_eq!(query.parse::<Command>(), Ok(Command::Query(1, 0, stale)));
+        let query = "q 1 3";
+        assert_eq!(query.parse::<Command>(), Ok(Command::Query(1, 3, stale)));
+        let query = "q 1 lease";
+        let lease = ReadConsistency::Lease;
+        assert_eq!(query.parse::<Command>(), Ok(Command::Query(1, 0, lease)));
+        let query = "q 1 2 quorum";
+        let quorum = ReadConsistency::Quorum;
+        assert_eq!(query.parse::<Command>(), Ok(Command::Query(1, 2, quorum)));
+        let query = "q 1 latest";
+        assert_eq!(query.parse::<Command>(), Err(ParseCommandError));
+        let put = "put x k 42";
+        assert_eq!(put.parse::<Command>(), Err(ParseCommandError));
+        let leader = "leader 2";
+        assert_eq!(leader.parse::<Command>(), Ok(Command::Leader(2)));
+        let fault = "fault 1 * drop=0.1 dup=0.05 reorder=0.2 delay=10-50";
+        let faults = LinkFaults {
+            drop_rate: 0.1,
+            dup_rate: 0.05,
+            reorder_rate: 0.2,
+            min_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+        };
+        assert_eq!(
+            fault.parse::<Command>(),
+            Ok(Command::Fault(Some(1), None, faults))
+        );
+        let fault = "f * 2 delay=20";
+        let faults = LinkFaults {
+            min_delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(20),
+            ..LinkFaults::default()
+        };
+        assert_eq!(
+            fault.parse::<Command>(),
+            Ok(Command::Fault(None, Some(2), faults))
+        );
+        let fault = "f 1 2 drop=2";
+        assert_eq!(fault.parse::<Command>(), Err(ParseCommandError));
+        let fault = "fault clear";
+        assert_eq!(fault.parse::<Command>(), Ok(Command::ClearFaults));
+        let kill = "kill 2";
+        assert_eq!(kill.parse::<Command>(), Ok(Command::Kill(2)));
+        let restart = "r 2";
+        assert_eq!(restart.parse::<Command>(), Ok(Command::Restart(2)));
+        let partition = "partition 1,2 | 3, 4 5";
+        assert_eq!(
+            partition.parse::<Command>(),
+            Ok(Command::Partition(vec![vec![1, 2], vec![3, 4, 5]]))
+        );
+        let partition = "partition 1,2 |";
+        assert_eq!(partition.parse::<Command>(), Err(ParseCommandError));
+        let isolate = "isolate 3";
+        assert_eq!(isolate.parse::<Command>(), Ok(Command::Isolate(3)));
+        let reconfigure = "rc 1 1,2,3,4,5";
+        assert_eq!(
+            reconfigure.parse::<Command>(),
+            Ok(Command::Reconfigure(1, (1..6).collect()))
+        );
+        let reconfigure = "reconfigure 1 1,2 | 3";
+        assert_eq!(reconfigure.parse::<Command>(), Err(ParseCommandError));
+        let request = parse_request("l 2");
+        assert_eq!(request, Ok((2, Command::Leader(2))));
+        assert_eq!(parse_request("kill 2"), Err(ParseCommandError));
+        let heal = "heal";
+        assert_eq!(heal.parse::<Command>(), Ok(Command::Heal));
         let exit = "exit";
         assert_eq!(exit.parse::<Command>(), Ok(Command::Exit));
         let error = "error";
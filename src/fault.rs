@@ -0,0 +1,196 @@
+//! Network faults injected by the `Broker`, to see how the protocol copes with
+//! a misbehaving network on top of the real TCP stack.
+
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How the link between two servers misbehaves.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkFaults {
+    pub drop_rate: f64,
+    pub dup_rate: f64,
+    /// Every datagram is held back for a delay drawn uniformly from this range.
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    /// Datagrams are delivered in order unless picked for reordering, in which
+    /// case their delay alone decides when they arrive, and they may overtake or
+    /// fall behind others.
+    pub reorder_rate: f64,
+}
+
+impl LinkFaults {
+    /// The delay of every copy of a datagram to deliver, if any, and whether they
+    /// may be reordered.
+    pub fn plan(&self, rng: &mut impl Rng) -> (Vec<Duration>, bool) {
+        if rng.gen_bool(self.drop_rate) {
+            return (vec![], false);
+        }
+        let copies = if rng.gen_bool(self.dup_rate) { 2 } else { 1 };
+        let delays = (0..copies)
+            .map(|_| {
+                let jitter = self.max_delay.saturating_sub(self.min_delay);
+                self.min_delay + jitter.mul_f64(rng.gen())
+            })
+            .collect();
+        (delays, rng.gen_bool(self.reorder_rate))
+    }
+}
+
+/// A link from a server to another, where `None` stands for any server.
+type Link = (Option<usize>, Option<usize>);
+
+/// The faults of every link, shared by the brokers and adjustable while they run.
+///
+/// When several settings cover a link, the most specific applies: `(src, dst)`, then `(src, *)`,
+/// `(*, dst)` and `(*, *)`.
+///
+/// Links can also be cut altogether, which drops everything sent over them
+/// whatever their faults are, until they're healed.
+///
+/// Only the receiving end applies the policy: a broker decides the fate of each
+/// datagram it reads off the wire, and sends its own untouched. So a link's faults
+/// only take effect if its destination's broker shares the policy, and a datagram
+/// a fault drops has still crossed the real network.
+#[derive(Debug, Default)]
+pub struct FaultPolicy {
+    links: Mutex<HashMap<Link, LinkFaults>>,
+    cut: Mutex<HashSet<(usize, usize)>>,
+}
+
+impl FaultPolicy {
+    pub fn set(&self, src: Option<usize>, dst: Option<usize>, faults: LinkFaults) {
+        self.links.lock().unwrap().insert((src, dst), faults);
+    }
+
+    /// Restore a reliable network.
+    pub fn clear(&self) {
+        self.links.lock().unwrap().clear();
+    }
+
+    /// Split `servers` into `groups` that can only talk within themselves, healing
+    /// any previous partition. Servers left out of every group are cut off from all
+    /// the others.
+    pub fn partition(&self, groups: &[Vec<usize>], servers: &[usize]) {
+        let group_of = |id| groups.iter().position(|group| group.contains(&id));
+        let mut cut = self.cut.lock().unwrap();
+        cut.clear();
+        for &a in servers {
+            for &b in servers {
+                if a != b && (group_of(a).is_none() || group_of(a) != group_of(b)) {
+                    cut.insert((a, b));
+                }
+            }
+        }
+    }
+
+    /// Cut server #`id` off from the other `servers`.
+    pub fn isolate(&self, id: usize, servers: &[usize]) {
+        let mut cut = self.cut.lock().unwrap();
+        for &other in servers.iter().filter(|&&other| other != id) {
+            cut.insert((id, other));
+            cut.insert((other, id));
+        }
+    }
+
+    /// Restore every link that was cut.
+    pub fn heal(&self) {
+        self.cut.lock().unwrap().clear();
+    }
+
+    pub fn link(&self, src: usize, dst: usize) -> LinkFaults {
+        if self.cut.lock().unwrap().contains(&(src, dst)) {
+            return LinkFaults {
+                drop_rate: 1.0,
+                ..LinkFaults::default()
+            };
+        }
+        let links = self.links.lock().unwrap();
+        [
+            (Some(src), Some(dst)),
+            (Some(src), None),
+            (None, Some(dst)),
+            (None, None),
+        ]
+        .iter()
+        .find_map(|key| links.get(key))
+        .cloned()
+        .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_most_specific_link_applies() {
+        let policy = FaultPolicy::default();
+        let faults = |drop_rate| LinkFaults {
+            drop_rate,
+            ..LinkFaults::default()
+        };
+        policy.set(None, None, faults(0.1));
+        policy.set(None, Some(2), faults(0.2));
+        policy.set(Some(1), None, faults(0.3));
+        policy.set(Some(1), Some(2), faults(0.4));
+        assert_eq!(policy.link(1, 2), faults(0.4));
+        assert_eq!(policy.link(1, 3), faults(0.3));
+        assert_eq!(policy.link(3, 2), faults(0.2));
+        assert_eq!(policy.link(3, 1), faults(0.1));
+        policy.clear();
+        assert_eq!(policy.link(1, 2), LinkFaults::default());
+    }
+
+    #[test]
+    fn test_partition() {
+        let policy = FaultPolicy::default();
+        let servers = [1, 2, 3, 4, 5];
+        let reachable = |src, dst| policy.link(src, dst).drop_rate < 1.0;
+        policy.partition(&[vec![1, 2], vec![3, 4]], &servers);
+        assert!(reachable(1, 2) && reachable(2, 1) && reachable(3, 4));
+        assert!(!reachable(1, 3) && !reachable(4, 2));
+        assert!(!reachable(5, 1) && !reachable(3, 5));
+        // Clients aren't part of the partition.
+        assert!(reachable(0, 5) && reachable(1, 0));
+
+        policy.partition(&[vec![1, 2, 3, 4, 5]], &servers);
+        policy.isolate(3, &servers);
+        assert!(reachable(1, 2) && reachable(0, 3));
+        assert!(!reachable(3, 1) && !reachable(5, 3));
+        policy.heal();
+        assert!(reachable(3, 1) && reachable(5, 3));
+    }
+
+    #[test]
+    fn test_plan() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let reliable = LinkFaults::default();
+        assert_eq!(
+            reliable.plan(&mut rng),
+            (vec![Duration::from_secs(0)], false)
+        );
+
+        let lossy = LinkFaults {
+            drop_rate: 1.0,
+            ..LinkFaults::default()
+        };
+        assert_eq!(lossy.plan(&mut rng), (vec![], false));
+
+        let (min_delay, max_delay) = (Duration::from_millis(10), Duration::from_millis(20));
+        let noisy = LinkFaults {
+            dup_rate: 1.0,
+            reorder_rate: 1.0,
+            min_delay,
+            max_delay,
+            ..LinkFaults::default()
+        };
+        let (delays, reorder) = noisy.plan(&mut rng);
+        assert_eq!(delays.len(), 2);
+        assert!(delays.iter().all(|d| (min_delay..=max_delay).contains(d)));
+        assert!(reorder);
+    }
+}
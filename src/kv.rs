@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::state_machine::StateMachine;
+
+/// A command to the replicated key-value store.
+///
+/// Reads go through the log like writes do, so every command, `Get` included,
+/// takes effect at a single point between its proposal and its answer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum KvCommand {
+    Get {
+        key: String,
+    },
+    Put {
+        key: String,
+        value: String,
+    },
+    Delete {
+        key: String,
+    },
+    /// Set `key` to `value` if it currently holds `expected`, where `None` means
+    /// the key must be absent.
+    Cas {
+        key: String,
+        expected: Option<String>,
+        value: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum KvOutput {
+    /// What the key held before the command.
+    Value(Option<String>),
+    /// Whether a `Cas` took place, and what the key held before it.
+    Cas {
+        swapped: bool,
+        previous: Option<String>,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct KvStore {
+    map: HashMap<String, String>,
+}
+
+impl StateMachine<KvCommand> for KvStore {
+    type Output = KvOutput;
+    type Snapshot = HashMap<String, String>;
+
+    fn apply(&mut self, cmd: KvCommand) -> KvOutput {
+        match cmd {
+            KvCommand::Get { key } => KvOutput::Value(self.map.get(&key).cloned()),
+            KvCommand::Put { key, value } => KvOutput::Value(self.map.insert(key, value)),
+            KvCommand::Delete { key } => KvOutput::Value(self.map.remove(&key)),
+            KvCommand::Cas {
+                key,
+                expected,
+                value,
+            } => {
+                let previous = self.map.get(&key).cloned();
+                let swapped = previous == expected;
+                if swapped {
+                    self.map.insert(key, value);
+                }
+                KvOutput::Cas { swapped, previous }
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.map.clone()
+    }
+
+    fn restore(&mut self, snapshot: Self::Snapshot) {
+        self.map = snapshot;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_kv_commands() {
+        let mut kv = KvStore::default();
+        let key = || "k".to_string();
+        let some = |s: &str| Some(s.to_string());
+
+        assert_eq!(
+            kv.apply(KvCommand::Get { key: key() }),
+            KvOutput::Value(None)
+        );
+        let put = KvCommand::Put {
+            key: key(),
+            value: "a".to_string(),
+        };
+        assert_eq!(kv.apply(put), KvOutput::Value(None));
+
+        let cas = |expected: Option<String>, value: &str| KvCommand::Cas {
+            key: key(),
+            expected,
+            value: value.to_string(),
+        };
+        assert_eq!(
+            kv.apply(cas(None, "b")),
+            KvOutput::Cas {
+                swapped: false,
+                previous: some("a")
+            }
+        );
+        assert_eq!(
+            kv.apply(cas(some("a"), "b")),
+            KvOutput::Cas {
+                swapped: true,
+                previous: some("a")
+            }
+        );
+        assert_eq!(
+            kv.apply(KvCommand::Get { key: key() }),
+            KvOutput::Value(some("b"))
+        );
+        assert_eq!(
+            kv.apply(KvCommand::Delete { key: key() }),
+            KvOutput::Value(some("b"))
+        );
+        assert_eq!(
+            kv.apply(cas(None, "c")),
+            KvOutput::Cas {
+                swapped: true,
+                previous: None
+            }
+        );
+    }
+}
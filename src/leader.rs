@@ -0,0 +1,259 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+
+use crate::paxos::{AcceptedProposal, SequenceNumber};
+
+/// A server's part in electing a distinguished proposer.
+///
+/// A candidate asks the acceptors to promise its sequence number for every instance
+/// from its first unchosen one onwards. Once a phase-1 quorum did, it's the leader: it can
+/// send `Accept`s right away for new instances, and keeps the others from starting
+/// elections of their own with heartbeats.
+///
+/// Acceptors acknowledge the heartbeats of a leader they haven't promised anything
+/// higher than, and by doing so grant it a lease: they won't promise anything to
+/// another server for a while. Acknowledgements are what lets the leader answer
+/// linearizable reads without going through the log.
+#[derive(Debug, Clone)]
+pub enum Role<V> {
+    /// Following the leader elected with the given sequence number, if we know of one.
+    Follower {
+        leader: Option<(usize, SequenceNumber)>,
+    },
+    Candidate(Candidacy<V>),
+    /// Leading with the promises gathered for `seq`, for every instance from `from` on.
+    Leader {
+        seq: SequenceNumber,
+        from: usize,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Candidacy<V> {
+    pub seq: SequenceNumber,
+    pub from: usize,
+    pub promised: BTreeSet<usize>,
+    /// The highest-numbered proposal each instance has accepted, as reported so far.
+    pub accepted: BTreeMap<usize, AcceptedProposal<V>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Leadership<V> {
+    role: Role<V>,
+    heartbeat_interval: Duration,
+    /// When a follower gives up on its leader, or a candidate on its election.
+    election_deadline: Instant,
+    next_heartbeat: Instant,
+    /// The number of the last heartbeat we sent as leader, when, and who acknowledged it.
+    beat: usize,
+    beat_sent: Instant,
+    acks: BTreeSet<usize>,
+    /// The last heartbeat enough acceptors acknowledged to confirm we still led once
+    /// it was sent.
+    confirmed: usize,
+    /// Until when no other leader can be elected.
+    lease: Option<Instant>,
+    /// The lease we granted to the leader elected with the given sequence number.
+    granted: Option<(SequenceNumber, Instant)>,
+}
+
+impl<V> Leadership<V> {
+    pub fn new(heartbeat_interval: Duration, election_deadline: Instant) -> Self {
+        Self {
+            role: Role::Follower { leader: None },
+            heartbeat_interval,
+            election_deadline,
+            next_heartbeat: election_deadline,
+            beat: 0,
+            beat_sent: election_deadline,
+            acks: BTreeSet::new(),
+            confirmed: 0,
+            lease: None,
+            granted: None,
+        }
+    }
+
+    /// Who we believe is leading, ourselves included.
+    pub fn leader(&self) -> Option<usize> {
+        match self.role {
+            Role::Follower { leader } => leader.map(|(id, _)| id),
+            Role::Candidate(_) => None,
+            Role::Leader { seq, .. } => Some(seq.server_id()),
+        }
+    }
+
+    /// The sequence number we lead with, if we are the leader.
+    pub fn ballot(&self) -> Option<SequenceNumber> {
+        match self.role {
+            Role::Leader { seq, .. } => Some(seq),
+            _ => None,
+        }
+    }
+
+    /// The first instance our candidacy or leadership covers.
+    pub fn from(&self) -> Option<usize> {
+        match self.role {
+            Role::Follower { .. } => None,
+            Role::Candidate(ref candidacy) => Some(candidacy.from),
+            Role::Leader { from, .. } => Some(from),
+        }
+    }
+
+    pub fn election_due(&self, now: Instant) -> bool {
+        self.ballot().is_none() && now >= self.election_deadline
+    }
+
+    /// Whether the leader should send a heartbeat now. Schedules the next one if so,
+    /// and starts counting acknowledgements for this one.
+    pub fn heartbeat_due(&mut self, now: Instant) -> bool {
+        if self.ballot().is_some() && now >= self.next_heartbeat {
+            self.next_heartbeat = now + self.heartbeat_interval;
+            self.beat += 1;
+            self.beat_sent = now;
+            self.acks.clear();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Send the next heartbeat as soon as possible rather than on schedule.
+    pub fn hasten_heartbeat(&mut self, now: Instant) {
+        self.next_heartbeat = self.next_heartbeat.min(now);
+    }
+
+    /// The number of the last heartbeat sent.
+    pub fn beat(&self) -> usize {
+        self.beat
+    }
+
+    /// Count `src`'s acknowledgement of heartbeat `beat` of our leadership with `seq`.
+    /// Returns everyone who acknowledged it so far, unless it's outdated.
+    pub fn on_heartbeat_ack(
+        &mut self,
+        src: usize,
+        seq: SequenceNumber,
+        beat: usize,
+    ) -> Option<&BTreeSet<usize>> {
+        if self.ballot() != Some(seq) || beat != self.beat {
+            return None;
+        }
+        self.acks.insert(src);
+        Some(&self.acks)
+    }
+
+    /// Enough acceptors acknowledged the last heartbeat that nobody else can have
+    /// had a value chosen before it was sent.
+    pub fn confirm(&mut self) {
+        self.confirmed = self.beat;
+    }
+
+    /// The last heartbeat that confirmed our leadership.
+    pub fn confirmed(&self) -> usize {
+        self.confirmed
+    }
+
+    /// Enough acceptors acknowledged the last heartbeat that nobody else can be
+    /// elected for `duration` after it was sent.
+    pub fn extend_lease(&mut self, duration: Duration) {
+        self.lease = Some(self.beat_sent + duration);
+    }
+
+    pub fn has_lease(&self, now: Instant) -> bool {
+        self.ballot().is_some() && self.lease.is_some_and(|lease| now < lease)
+    }
+
+    /// Promise the leader elected with `seq` not to help anyone else lead until `until`.
+    pub fn grant_lease(&mut self, seq: SequenceNumber, until: Instant) {
+        self.granted = Some((seq, until));
+    }
+
+    /// The leader elected with the returned sequence number holds a lease we granted,
+    /// which keeps us from promising `seq` to its proposer.
+    pub fn lease_holder(&self, seq: SequenceNumber, now: Instant) -> Option<SequenceNumber> {
+        self.granted
+            .filter(|&(leader, until)| leader.server_id() != seq.server_id() && now < until)
+            .map(|(leader, _)| leader)
+    }
+
+    pub fn start_election(&mut self, seq: SequenceNumber, from: usize, deadline: Instant) {
+        self.role = Role::Candidate(Candidacy {
+            seq,
+            from,
+            promised: BTreeSet::new(),
+            accepted: BTreeMap::new(),
+        });
+        self.election_deadline = deadline;
+    }
+
+    /// Record a heartbeat of the leader elected with `seq`. Returns whether it
+    /// replaced the leader we knew of.
+    pub fn on_heartbeat(&mut self, seq: SequenceNumber, deadline: Instant) -> bool {
+        let known = match self.role {
+            Role::Follower { leader } => leader.map(|(_, seq)| seq),
+            Role::Candidate(ref candidacy) => Some(candidacy.seq),
+            Role::Leader { seq, .. } => Some(seq),
+        };
+        if known.is_some_and(|known| known > seq) {
+            return false;
+        }
+        self.election_deadline = deadline;
+        if known == Some(seq) {
+            return false;
+        }
+        self.role = Role::Follower {
+            leader: Some((seq.server_id(), seq)),
+        };
+        true
+    }
+
+    /// Count a promise for our candidacy with `seq`. Returns the finished candidacy
+    /// once the acceptors that promised make a quorum, at which point we're the leader.
+    pub fn on_promise(
+        &mut self,
+        src: usize,
+        seq: SequenceNumber,
+        accepted: Vec<(usize, AcceptedProposal<V>)>,
+        is_quorum: impl FnOnce(&BTreeSet<usize>) -> bool,
+        now: Instant,
+    ) -> Option<Candidacy<V>> {
+        let candidacy = match self.role {
+            Role::Candidate(ref mut candidacy) if candidacy.seq == seq => candidacy,
+            _ => return None,
+        };
+        if !candidacy.promised.insert(src) {
+            return None;
+        }
+        for (instance, proposal) in accepted {
+            match candidacy.accepted.get(&instance) {
+                Some(highest) if highest.seq() >= proposal.seq() => {}
+                _ => {
+                    candidacy.accepted.insert(instance, proposal);
+                }
+            }
+        }
+        if !is_quorum(&candidacy.promised) {
+            return None;
+        }
+        self.next_heartbeat = now;
+        self.lease = None;
+        let from = candidacy.from;
+        match std::mem::replace(&mut self.role, Role::Leader { seq, from }) {
+            Role::Candidate(candidacy) => Some(candidacy),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Our candidacy with `seq` was turned down.
+    pub fn on_rejected(&mut self, seq: SequenceNumber) {
+        if matches!(self.role, Role::Candidate(ref candidacy) if candidacy.seq == seq) {
+            self.role = Role::Follower { leader: None };
+        }
+    }
+
+    /// Someone else has been promised a higher sequence number than our ballot.
+    pub fn step_down(&mut self, deadline: Instant) {
+        self.role = Role::Follower { leader: None };
+        self.election_deadline = deadline;
+    }
+}
@@ -0,0 +1,21 @@
+#[macro_use]
+mod macros;
+
+#[cfg(test)]
+mod checker;
+pub mod client;
+pub mod cluster;
+mod codec;
+pub mod console;
+pub mod fault;
+pub mod kv;
+mod leader;
+pub mod network;
+pub mod paxos;
+pub mod quorum;
+mod session;
+#[cfg(test)]
+mod sim;
+pub mod state_machine;
+pub mod storage;
+mod wal;
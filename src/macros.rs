@@ -0,0 +1,18 @@
+macro_rules! log {
+    ($($tokens: tt)*) => {
+        {
+            // Let the test harness capture the output, so that only failing
+            // tests show it.
+            #[cfg(test)]
+            println!($($tokens)*);
+            #[cfg(not(test))]
+            {
+                use std::io::Write;
+                let stdout = std::io::stdout();
+                let mut handle = stdout.lock();
+                writeln!(handle, $($tokens)*).unwrap();
+                handle.flush().unwrap();
+            }
+        }
+    }
+}
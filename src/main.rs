@@ -1,10 +1,4 @@
-#![feature(try_trait)]
-
-use console::Console;
-
-mod console;
-mod network;
-mod paxos;
+use paxos::console::Console;
 
 fn main() {
     let console = Console::new();
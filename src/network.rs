@@ -1,76 +1,260 @@
+use bytes::Bytes;
+use futures::channel::mpsc;
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
 use tokio::stream::StreamExt;
+use tokio::time;
 
+use crate::codec::Codec;
+use crate::fault::FaultPolicy;
 use crate::paxos::*;
 
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(2);
+
 #[derive(Debug)]
 pub struct Broker {
     local_id: usize,
-    addr_by_id: HashMap<usize, SocketAddr>,
-    id_by_addr: HashMap<SocketAddr, usize>,
+    addr_by_id: RwLock<HashMap<usize, SocketAddr>>,
+    codec: Codec,
+    faults: Arc<FaultPolicy>,
 }
 
 impl Broker {
-    pub fn new(local_id: usize, servers_addr: HashMap<usize, SocketAddr>) -> Arc<Self> {
-        let id_by_addr: HashMap<SocketAddr, usize> =
-            servers_addr.iter().map(|(&k, &v)| (v, k)).collect();
+    /// A broker that injects the faults `faults` describes into the datagrams it
+    /// receives. They can be changed while it runs.
+    pub fn new(
+        local_id: usize,
+        servers_addr: HashMap<usize, SocketAddr>,
+        faults: Arc<FaultPolicy>,
+    ) -> Arc<Self> {
+        Self::with_codec(local_id, servers_addr, Codec::default(), faults)
+    }
 
+    pub fn with_codec(
+        local_id: usize,
+        servers_addr: HashMap<usize, SocketAddr>,
+        codec: Codec,
+        faults: Arc<FaultPolicy>,
+    ) -> Arc<Self> {
         let broker = Self {
             local_id,
-            addr_by_id: servers_addr,
-            id_by_addr,
+            addr_by_id: RwLock::new(servers_addr),
+            codec,
+            faults,
         };
         Arc::new(broker)
     }
 
-    pub async fn run(
+    /// Make server #`id` reachable at `addr`, e.g. when it joins the cluster.
+    pub fn add_peer(&self, id: usize, addr: SocketAddr) {
+        self.addr_by_id.write().unwrap().insert(id, addr);
+    }
+
+    pub fn id(&self) -> usize {
+        self.local_id
+    }
+
+    fn addr_of(&self, id: usize) -> Option<SocketAddr> {
+        self.addr_by_id.read().unwrap().get(&id).copied()
+    }
+
+    pub async fn run<V: Value, O: Value>(
         self: Arc<Self>,
-        tx: Tx<Incoming>,
-        rx: Rx<Outgoing>,
+        tx: Tx<Incoming<V, O>>,
+        rx: Rx<Outgoing<V, O>>,
     ) -> Result<(), tokio::io::Error> {
-        let mut listener = TcpListener::bind(self.addr_by_id[&self.local_id]).await?;
+        let addr = self.addr_of(self.local_id).unwrap();
+        let mut listener = TcpListener::bind(addr).await?;
         tokio::spawn(self.clone().serve_outflow(rx));
         while let Some(socket) = listener.incoming().next().await {
-            tokio::spawn(Self::serve_inflow(socket?, tx.clone()));
+            match socket {
+                Ok(socket) => {
+                    tokio::spawn(self.clone().serve_inflow(socket, tx.clone()));
+                }
+                Err(e) => log!("Server #{} fail to accept: {}", self.local_id, e),
+            }
         }
         Ok(())
     }
 
-    pub async fn read_incoming(
-        socket: &mut TcpStream,
-    ) -> Result<(usize, Datagram), tokio::io::Error> {
-        let mut buf = vec![0u8; 512];
-        let src = socket.read_u64().await?;
-        let src = src as usize;
-        let len = socket.read_u64().await? as usize;
-        socket.read_exact(&mut buf[..len]).await?;
-        let decoded: Datagram = bincode::deserialize(&buf[..len]).unwrap();
-        Ok((src, decoded))
+    async fn serve_inflow<V: Value, O: Value>(
+        self: Arc<Self>,
+        mut socket: TcpStream,
+        tx: Tx<Incoming<V, O>>,
+    ) {
+        // When the last datagram from this connection that's kept in order is due.
+        let mut last_due = Instant::now();
+        loop {
+            match self.codec.read_frame(&mut socket).await {
+                // The server is gone, so hang up on whoever is still talking to it.
+                Ok(_) if tx.is_closed() => return,
+                Ok((src, dgram)) => self.deliver(Incoming { src, dgram }, &tx, &mut last_due),
+                Err(e) => {
+                    if !e.is_eof() {
+                        log!(
+                            "Server #{} close connection from {:?}: {}",
+                            self.local_id,
+                            socket.peer_addr(),
+                            e
+                        );
+                    }
+                    // Dropping the socket closes the connection.
+                    return;
+                }
+            }
+        }
     }
 
-    async fn serve_inflow(mut socket: TcpStream, tx: Tx<Incoming>) {
-        while let Ok((src, dgram)) = Self::read_incoming(&mut socket).await {
-            tx.unbounded_send(Incoming { src, dgram }).unwrap();
+    /// Pass `incoming` on to `tx`, unless the faults of its link drop, delay or
+    /// duplicate it.
+    ///
+    /// Faults are injected here, on the receiving side only, so that they also
+    /// cover datagrams from clients, which don't go through a broker to send.
+    /// `serve_outflow` sends everything as is.
+    fn deliver<V: Value, O: Value>(
+        &self,
+        incoming: Incoming<V, O>,
+        tx: &Tx<Incoming<V, O>>,
+        last_due: &mut Instant,
+    ) {
+        let faults = self.faults.link(incoming.src, self.local_id);
+        let (delays, reorder) = faults.plan(&mut rand::thread_rng());
+        if delays.is_empty() {
+            log!(
+                "Server #{} drop dgram from #{}",
+                self.local_id,
+                incoming.src
+            );
+        }
+        let now = Instant::now();
+        for delay in delays {
+            let mut due = now + delay;
+            if !reorder {
+                due = due.max(*last_due);
+                *last_due = due;
+            }
+            if due <= now {
+                let _ = tx.unbounded_send(incoming.clone());
+            } else {
+                let (tx, incoming) = (tx.clone(), incoming.clone());
+                tokio::spawn(async move {
+                    time::delay_until(time::Instant::from_std(due)).await;
+                    let _ = tx.unbounded_send(incoming);
+                });
+            }
         }
     }
 
-    async fn serve_outflow(self: Arc<Self>, mut rx: Rx<Outgoing>) {
+    async fn serve_outflow<V: Value, O: Value>(self: Arc<Self>, mut rx: Rx<Outgoing<V, O>>) {
+        // One queue per peer, drained by a task owning the connection to it.
+        let mut queues: HashMap<usize, Tx<Bytes>> = HashMap::new();
         while let Some(Outgoing { dst, dgram }) = rx.next().await {
-            dst.iter().for_each(|id| {
-                let addr = self.addr_by_id[id];
-                let dgram = dgram.clone();
-                let local_id = self.local_id;
-                let send_task = async move {
-                    let mut stream = TcpStream::connect(addr).await.unwrap();
-                    let buf = dgram.encode_with_src(local_id);
-                    stream.write_all(&buf).await.unwrap();
-                };
-                tokio::spawn(send_task);
-            });
+            let buf = dgram.encode_with_src(self.local_id);
+            if !self.codec.fits(&buf) {
+                log!(
+                    "Server #{} drop oversized dgram of {} bytes",
+                    self.local_id,
+                    buf.len()
+                );
+                continue;
+            }
+            for id in dst {
+                if self.addr_of(id).is_none() {
+                    log!("Server #{} drop dgram to unknown #{}", self.local_id, id);
+                    continue;
+                }
+                let queue = queues.entry(id).or_insert_with(|| {
+                    let (tx, rx) = mpsc::unbounded();
+                    tokio::spawn(self.clone().serve_peer(id, rx));
+                    tx
+                });
+                // The peer task only quits when we drop its queue.
+                queue.unbounded_send(buf.clone()).unwrap();
+            }
+        }
+    }
+
+    /// Deliver everything queued for peer #`id` over a single long-lived connection.
+    ///
+    /// The connection is only opened once there is something to send. When it
+    /// breaks, the message being written is kept and we reconnect with exponential
+    /// backoff, while later messages wait in the queue. Each attempt goes to the
+    /// address the peer has at the time, which `add_peer` may have changed.
+    async fn serve_peer(self: Arc<Self>, id: usize, mut rx: Rx<Bytes>) {
+        let mut stream: Option<TcpStream> = None;
+        let mut backoff = MIN_RECONNECT_BACKOFF;
+        let mut pending: Option<Bytes> = None;
+        loop {
+            let buf = match pending.take() {
+                Some(buf) => buf,
+                None => match rx.next().await {
+                    Some(buf) => buf,
+                    None => return,
+                },
+            };
+            // A peer that went away, like a client that's done, closed its end, but
+            // writing there would still succeed and lose the datagram.
+            if stream.as_mut().is_some_and(closed_by_peer) {
+                stream = None;
+            }
+            let conn = match stream {
+                Some(ref mut conn) => conn,
+                None => {
+                    let addr = match self.addr_of(id) {
+                        Some(addr) => addr,
+                        None => {
+                            log!("Server #{} drop dgram to unknown #{}", self.local_id, id);
+                            continue;
+                        }
+                    };
+                    match TcpStream::connect(addr).await {
+                        Ok(conn) => {
+                            backoff = MIN_RECONNECT_BACKOFF;
+                            stream.get_or_insert(conn)
+                        }
+                        Err(e) => {
+                            log!(
+                                "Server #{} fail to connect #{}: {}, retry in {:?}",
+                                self.local_id,
+                                id,
+                                e,
+                                backoff
+                            );
+                            pending = Some(buf);
+                            time::delay_for(backoff).await;
+                            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                            continue;
+                        }
+                    }
+                }
+            };
+            if let Err(e) = conn.write_all(&buf).await {
+                log!(
+                    "Server #{} lost connection to #{}: {}",
+                    self.local_id,
+                    id,
+                    e
+                );
+                stream = None;
+                pending = Some(buf);
+            }
         }
     }
 }
+
+/// Whether the peer closed `conn`, as far as we can tell without waiting.
+fn closed_by_peer(conn: &mut TcpStream) -> bool {
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    // Peers never send anything back over the connections we open.
+    matches!(
+        conn.poll_peek(&mut cx, &mut [0]),
+        Poll::Ready(Ok(0)) | Poll::Ready(Err(_))
+    )
+}
@@ -1,27 +1,44 @@
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::channel::mpsc;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Debug;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::stream::StreamExt;
+use tokio::time;
+
+use crate::leader::{Candidacy, Leadership};
+use crate::quorum::{Majority, QuorumSystem};
+use crate::session::{Seen, Sessions};
+use crate::state_machine::StateMachine;
+use crate::storage::Storage;
 
 pub type Tx<T> = mpsc::UnboundedSender<T>;
 pub type Rx<T> = mpsc::UnboundedReceiver<T>;
 
-macro_rules! log {
-    ($($tokens: tt)*) => {
-        {
-            use std::io::Write;
-            let stdout = std::io::stdout();
-            let mut handle = stdout.lock();
-            writeln!(handle, $($tokens)*).unwrap();
-            handle.flush().unwrap();
-            // println!($($tokens)*);
-        }
+/// How often `run` checks the proposers' timers.
+pub const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Add a line to the log of the server's current effects.
+macro_rules! trace {
+    ($paxos: expr, $($tokens: tt)*) => {
+        $paxos.effects.log.push(format!($($tokens)*))
     }
 }
 
-pub type ValueType = u32;
+/// Anything the servers can agree on.
+pub trait Value: Serialize + DeserializeOwned + Clone + PartialEq + Debug + Send + 'static {}
+
+impl<T> Value for T where
+    T: Serialize + DeserializeOwned + Clone + PartialEq + Debug + Send + 'static
+{
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct SequenceNumber {
@@ -31,11 +48,7 @@ pub struct SequenceNumber {
 
 impl PartialOrd for SequenceNumber {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        if self.seq == other.seq {
-            Some(self.server_id.cmp(&other.server_id))
-        } else {
-            Some(self.seq.cmp(&other.seq))
-        }
+        Some(self.cmp(other))
     }
 }
 
@@ -50,61 +63,274 @@ impl Ord for SequenceNumber {
 }
 
 impl SequenceNumber {
-    fn new(server_id: usize, seq: usize) -> Self {
+    pub fn new(server_id: usize, seq: usize) -> Self {
         Self { server_id, seq }
     }
 
+    pub fn server_id(&self) -> usize {
+        self.server_id
+    }
     fn increase(&mut self) {
         self.seq += 1;
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-pub struct AcceptedProposal {
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AcceptedProposal<V> {
     seq: SequenceNumber,
-    val: ValueType,
+    val: V,
 }
 
-impl AcceptedProposal {
-    fn new(seq: SequenceNumber, val: ValueType) -> Self {
+impl<V> AcceptedProposal<V> {
+    pub fn new(seq: SequenceNumber, val: V) -> Self {
         Self { seq, val }
     }
+
+    pub fn seq(&self) -> SequenceNumber {
+        self.seq
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum Request {
+/// The log up to `upto`, compacted into the state it leaves behind.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    /// Every instance below this one is covered.
+    pub upto: usize,
+    /// The acceptors in effect from each instance on, as of `upto`.
+    pub configs: BTreeMap<usize, BTreeSet<usize>>,
+    /// The state machine's `Snapshot`, encoded with bincode.
+    pub state: Vec<u8>,
+    /// The clients' sessions, encoded with bincode.
+    pub sessions: Vec<u8>,
+}
+
+/// Tells a client's calls apart: the `seq`th call of client #`client`. Retries of a
+/// call keep its id, and answers carry it back.
+///
+/// A client makes one call at a time, with a higher `seq` than any before, even
+/// across restarts: the sessions take a command with a lower `seq` than the last one
+/// that took effect for its client for a leftover, and drop it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestId {
+    pub client: usize,
+    pub seq: u64,
+}
+
+/// What a slot of the replicated log holds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Entry<V> {
+    /// Fills a slot a new leader found no value for, so that later slots aren't held up.
+    Noop,
+    /// A command of the client's call `id`, which takes effect at most once however
+    /// many times it's chosen.
+    Value { id: RequestId, value: V },
+    /// Commands of several calls, proposed together to save rounds. They're applied
+    /// in order, each like a `Value`.
+    Batch(Vec<(RequestId, V)>),
+    /// Makes `members` the acceptors, from `PaxosConfig::reconfig_window` instances
+    /// after the one it's chosen in.
+    Reconfigure(BTreeSet<usize>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Request<V> {
     Propose {
-        value: ValueType,
+        id: RequestId,
+        value: V,
+    },
+    /// Ask for `members` to become the acceptors, through the log.
+    Reconfigure {
+        id: RequestId,
+        members: BTreeSet<usize>,
+    },
+    /// A `Propose` or `Reconfigure` a follower passed on to the leader. The result
+    /// goes to the client that made the call `id`.
+    Forward {
+        id: RequestId,
+        value: Entry<V>,
     },
     Prepare {
+        instance: usize,
         seq: SequenceNumber,
     },
     Accept {
+        instance: usize,
         seq: SequenceNumber,
-        value: ValueType,
+        value: Entry<V>,
     },
     Learn {
-        value: ValueType,
+        instance: usize,
+        value: Entry<V>,
+    },
+    /// Ask for the value chosen in `instance`, as up to date as `consistency` says.
+    Query {
+        id: RequestId,
+        instance: usize,
+        consistency: ReadConsistency,
+    },
+    /// A linearizable `Query` a follower passed on to the leader. The answer goes to
+    /// the client that made the call `id`.
+    ForwardQuery {
+        id: RequestId,
+        instance: usize,
+        consistency: ReadConsistency,
+    },
+    /// Phase 1 for every instance from `from` onwards at once, sent by a leader candidate.
+    Elect {
+        from: usize,
+        seq: SequenceNumber,
+    },
+    /// Sent periodically by the leader elected with `seq`, whose log has no holes
+    /// below `chosen_upto`. `beat` numbers them, so acknowledgements can be told apart.
+    Heartbeat {
+        seq: SequenceNumber,
+        chosen_upto: usize,
+        beat: usize,
+    },
+    /// Ask for the chosen values from `from` onwards, to be sent as `Learn`s.
+    CatchUp {
+        from: usize,
+    },
+    /// Everything chosen below `upto`, sent instead of the `Learn`s, promises or
+    /// acceptances a server asked for when they've been compacted away. A `Snapshot`
+    /// may not fit in a datagram, so it's encoded into `len` bytes, sent in chunks:
+    /// `data` goes at `offset`.
+    InstallSnapshot {
+        upto: usize,
+        offset: usize,
+        len: usize,
+        data: Vec<u8>,
+    },
+    /// Ask who the server thinks is leading.
+    Leader {
+        id: RequestId,
     },
-    Query,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum Response {
-    Prepare(Option<AcceptedProposal>),
-    Accept { seq: SequenceNumber },
-    Query { val: Option<ValueType> },
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Response<V, O> {
+    Prepare {
+        instance: usize,
+        seq: SequenceNumber,
+        accepted: Option<AcceptedProposal<Entry<V>>>,
+    },
+    Accept {
+        instance: usize,
+        seq: SequenceNumber,
+    },
+    /// The acceptor has promised `promised`, which is higher than the `seq` it was asked to prepare.
+    PrepareRejected {
+        instance: usize,
+        seq: SequenceNumber,
+        promised: SequenceNumber,
+    },
+    /// The acceptor has promised `promised`, which is higher than the `seq` it was asked to accept.
+    AcceptRejected {
+        instance: usize,
+        seq: SequenceNumber,
+        promised: SequenceNumber,
+    },
+    /// A promise for the candidate with `seq`, along with every proposal accepted
+    /// from the instance it asked for onwards.
+    Elect {
+        seq: SequenceNumber,
+        accepted: Vec<(usize, AcceptedProposal<Entry<V>>)>,
+    },
+    /// The acceptor has promised `promised`, which is higher than the candidate's `seq`,
+    /// or holds a lease for the leader elected with `promised`.
+    ElectRejected {
+        seq: SequenceNumber,
+        promised: SequenceNumber,
+    },
+    Query {
+        id: RequestId,
+        instance: usize,
+        val: Option<Entry<V>>,
+    },
+    /// A `Query` couldn't be answered: `instance` was compacted into a snapshot, or,
+    /// for a linearizable one, the server isn't leading or couldn't make sure it
+    /// still was in time.
+    QueryFailed { id: RequestId, instance: usize },
+    /// The acceptor hasn't promised anything higher than `seq`, and grants its leader
+    /// a lease until it hears of it again.
+    Heartbeat { seq: SequenceNumber, beat: usize },
+    /// The outcome of a `Request::Propose`, sent back to whoever asked for it.
+    Propose {
+        id: RequestId,
+        value: V,
+        result: ProposeResult<O>,
+    },
+    /// The outcome of a `Request::Reconfigure`.
+    Reconfigure {
+        id: RequestId,
+        members: BTreeSet<usize>,
+        result: ProposeResult<()>,
+    },
+    Leader {
+        id: RequestId,
+        leader: Option<usize>,
+    },
+}
+
+impl<V, O> Response<V, O> {
+    /// The client's call this answers, unless it's for another server.
+    pub fn request_id(&self) -> Option<RequestId> {
+        match *self {
+            Response::Query { id, .. }
+            | Response::QueryFailed { id, .. }
+            | Response::Propose { id, .. }
+            | Response::Reconfigure { id, .. }
+            | Response::Leader { id, .. } => Some(id),
+            _ => None,
+        }
+    }
+}
+
+/// How up to date the answer to a `Query` must be.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ReadConsistency {
+    /// Whatever the server has learned so far, which may be behind.
+    Stale,
+    /// Answered by the leader from its own log while it holds a lease, i.e. while
+    /// acceptors won't help anyone else lead. Relies on clocks drifting apart no more
+    /// than `PaxosConfig::clock_drift`. Without a lease, it's a `Quorum` read.
+    Lease,
+    /// Answered by the leader once enough acceptors confirmed it's still leading.
+    Quorum,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ProposeResult<O> {
+    /// The value was chosen and applied, yielding `output`.
+    Chosen { instance: usize, output: O },
+    /// The value may or may not be chosen in `instance`: the server gave up on it
+    /// after `PaxosConfig::max_attempts` rounds, though acceptors may hold it, or
+    /// caught up through a snapshot covering `instance` before it knew what was
+    /// chosen there.
+    Unknown { instance: usize },
+}
+
+impl<O> ProposeResult<O> {
+    pub fn map<P>(self, f: impl FnOnce(O) -> P) -> ProposeResult<P> {
+        match self {
+            ProposeResult::Chosen { instance, output } => ProposeResult::Chosen {
+                instance,
+                output: f(output),
+            },
+            ProposeResult::Unknown { instance } => ProposeResult::Unknown { instance },
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum Datagram {
-    Request(Request),
-    Response(Response),
+pub enum Datagram<V, O> {
+    Request(Request<V>),
+    Response(Response<V, O>),
 }
 
-impl Datagram {
+impl<V: Value, O: Value> Datagram<V, O> {
     pub fn encode_with_src(&self, src: usize) -> Bytes {
-        const N: usize = std::mem::size_of::<usize>();
+        const N: usize = std::mem::size_of::<u64>();
 
         let data = bincode::serialize(&self).unwrap();
         let mut buf = BytesMut::with_capacity(2 * N + data.len());
@@ -116,266 +342,2660 @@ impl Datagram {
     }
 }
 
-#[derive(Debug)]
-pub struct Incoming {
+#[derive(Debug, Clone)]
+pub struct Incoming<V, O> {
     pub src: usize,
-    pub dgram: Datagram,
+    pub dgram: Datagram<V, O>,
 }
 
-#[derive(Debug)]
-pub struct Outgoing {
+#[derive(Debug, Clone)]
+pub struct Outgoing<V, O> {
     pub dst: HashSet<usize>,
-    pub dgram: Datagram,
+    pub dgram: Datagram<V, O>,
+}
+
+/// Something that happened to a server, reported to whoever embeds it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event<V, O> {
+    /// `value`, chosen in `instance`, was applied to the state machine. A batch
+    /// makes one such event per command.
+    Applied {
+        instance: usize,
+        value: V,
+        output: O,
+    },
+    /// The server now follows `leader`, which may be itself, or knows of no leader.
+    LeaderChanged { leader: Option<usize> },
+    /// `members` are the acceptors from `instance` on.
+    Reconfigured {
+        instance: usize,
+        members: BTreeSet<usize>,
+    },
+    /// The state machine was replaced with a snapshot of everything chosen below `upto`,
+    /// received from another server.
+    SnapshotInstalled { upto: usize },
+}
+
+/// What a server produced while handling an input: datagrams for the network to
+/// deliver, and events and log lines for its embedder.
+#[derive(Debug, Clone)]
+pub struct Effects<V, O> {
+    pub outgoing: Vec<Outgoing<V, O>>,
+    pub events: Vec<Event<V, O>>,
+    /// Lines describing what the server did, for the embedder to show if it wants.
+    pub log: Vec<String>,
+}
+
+impl<V, O> Default for Effects<V, O> {
+    fn default() -> Self {
+        Self {
+            outgoing: vec![],
+            events: vec![],
+            log: vec![],
+        }
+    }
+}
+
+/// Tuning knobs of the proposer.
+#[derive(Debug, Clone)]
+pub struct PaxosConfig {
+    /// How long a round may wait for a quorum before it's abandoned.
+    pub proposal_timeout: Duration,
+    /// How many rounds a proposal may run before the server gives up on it.
+    pub max_attempts: usize,
+    /// The random delay before a new round is drawn from `[0, backoff_base * 2^n]`
+    /// after the `n+1`-th failed round, capped at `backoff_max`.
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    /// How often the leader tells the others it's still there.
+    pub heartbeat_interval: Duration,
+    /// How long a follower waits without hearing from a leader before it runs for
+    /// election itself. A random delay of up to the same length is added on top.
+    pub election_timeout: Duration,
+    /// Seeds the randomized timers, for runs that must be reproducible.
+    /// Drawn from the OS if not set.
+    pub seed: Option<u64>,
+    /// A configuration chosen in instance `i` takes effect at instance `i + reconfig_window`.
+    /// Proposers need to know the acceptors of an instance to run it, so it's also
+    /// how far past the first unchosen instance they may go.
+    pub reconfig_window: usize,
+    /// Which acceptors make a quorum in each phase. Every server of a cluster must use the same.
+    pub quorums: Arc<dyn QuorumSystem>,
+    /// How long an acceptor refuses to help anyone else lead after acknowledging a
+    /// heartbeat, and so how long the leader may serve lease reads after sending one.
+    /// It should be below `election_timeout`, or leases hold up elections.
+    pub lease_duration: Duration,
+    /// How much faster or slower than real time any server's clock may run, e.g.
+    /// 0.01 for 1%. The leader shortens its leases accordingly.
+    pub clock_drift: f64,
+    /// Snapshot the state machine and drop the log below it every time this many more
+    /// instances are applied. Never, if not set.
+    pub snapshot_interval: Option<usize>,
+    /// How many instances a client's session outlives the last command of its that
+    /// took effect. Retries that come later may take effect again. Every server of a
+    /// cluster must use the same.
+    pub session_timeout: usize,
+    /// How long a proposer holds a client's command back, waiting for more to
+    /// propose in the same instance. Every command is proposed right away if zero.
+    pub batch_window: Duration,
+    /// A batch is proposed as soon as it holds this many commands.
+    pub max_batch: usize,
+    /// Snapshots are sent in chunks of this many bytes, which must leave room in a
+    /// datagram below the broker's frame size limit.
+    pub snapshot_chunk_size: usize,
+    /// How long a server waits before sending its snapshot again to a server that
+    /// keeps asking about what it covers.
+    pub snapshot_resend_interval: Duration,
+}
+
+impl Default for PaxosConfig {
+    fn default() -> Self {
+        Self {
+            proposal_timeout: Duration::from_millis(500),
+            max_attempts: 5,
+            backoff_base: Duration::from_millis(50),
+            backoff_max: Duration::from_secs(1),
+            heartbeat_interval: Duration::from_millis(200),
+            election_timeout: Duration::from_secs(1),
+            seed: None,
+            reconfig_window: 16,
+            quorums: Arc::new(Majority),
+            lease_duration: Duration::from_millis(500),
+            clock_drift: 0.01,
+            snapshot_interval: Some(1024),
+            session_timeout: 100_000,
+            batch_window: Duration::from_millis(0),
+            max_batch: 64,
+            snapshot_chunk_size: 256 * 1024,
+            snapshot_resend_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Timer {
+    /// The current round is abandoned at this point.
+    Timeout(Instant),
+    /// Backing off; a new round starts at this point.
+    Retry(Instant),
 }
 
-#[derive(Debug)]
-struct Proposal {
+impl Timer {
+    fn deadline(&self) -> Instant {
+        match *self {
+            Timer::Timeout(at) | Timer::Retry(at) => at,
+        }
+    }
+}
+
+/// How long to wait before running for leader: `timeout`, plus up to as much again
+/// at random so that servers rarely run against each other.
+fn election_delay(rng: &mut SmallRng, timeout: Duration) -> Duration {
+    timeout + timeout.mul_f64(rng.gen::<f64>())
+}
+
+#[derive(Debug, Clone)]
+struct Proposal<V> {
+    // The calls to report the result to. Nobody waits for what a new leader re-proposes.
+    clients: Vec<RequestId>,
     seq: SequenceNumber,
-    value: Option<ValueType>,
-    wanted_value: ValueType,
+    value: Option<Entry<V>>,
+    wanted_value: Entry<V>,
     highest_seq: Option<SequenceNumber>,
-    prepared: HashSet<usize>,
-    accepted: HashSet<usize>,
+    prepared: BTreeSet<usize>,
+    accepted: BTreeSet<usize>,
+    attempts: usize,
+    timer: Timer,
+}
+
+/// Client commands held back, to be proposed together in one instance.
+#[derive(Debug, Clone)]
+struct PendingBatch<V> {
+    commands: Vec<(RequestId, V)>,
+    // When they're proposed, however few there are.
+    deadline: Instant,
 }
 
-#[derive(Debug)]
-pub struct Paxos {
+/// A linearizable `Query` the leader holds until it may answer.
+#[derive(Debug, Clone)]
+struct PendingRead {
+    id: RequestId,
+    instance: usize,
+    // Everything chosen before the query arrived lies below this instance.
+    read_index: usize,
+    // The heartbeat that must confirm our leadership first, unless the lease covered it.
+    beat: Option<usize>,
+    deadline: Instant,
+}
+
+/// A snapshot coming from server #`src` in chunks, put together in order.
+#[derive(Debug, Clone)]
+struct IncomingSnapshot {
+    src: usize,
+    upto: usize,
+    len: usize,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Paxos<V, S, M: StateMachine<V>> {
     local_id: usize,
-    peers_id: HashSet<usize>,
-    last_promised: Option<SequenceNumber>,
-    chosen: Option<ValueType>,
-    last_accepted_proposal: Option<AcceptedProposal>,
-    proposal: Option<Proposal>,
+    // The acceptors in effect from each instance on, starting with those we were
+    // created with at instance 0.
+    configs: BTreeMap<usize, BTreeSet<usize>>,
+    storage: S,
+    // Proposals in flight, indexed by the instance they are proposed into.
+    proposals: BTreeMap<usize, Proposal<V>>,
     current_seq: SequenceNumber,
-    tx: Tx<Outgoing>,
-    rx: Rx<Incoming>,
+    leadership: Leadership<Entry<V>>,
+    state_machine: M,
+    // Replicated along with the state machine, to apply clients' commands only once.
+    sessions: Sessions<M::Output>,
+    // The next instance to apply to the state machine.
+    applied_upto: usize,
+    // Calls waiting for the output of the value chosen in an instance.
+    waiting: BTreeMap<usize, Vec<RequestId>>,
+    batch: Option<PendingBatch<V>>,
+    reads: Vec<PendingRead>,
+    // When we last sent our snapshot to each server.
+    snapshot_sent: BTreeMap<usize, Instant>,
+    incoming_snapshot: Option<IncomingSnapshot>,
+    config: PaxosConfig,
+    rng: SmallRng,
+    // What we've produced since the last input was handed to us.
+    effects: Effects<V, M::Output>,
 }
 
-impl Paxos {
+impl<V: Value, S: Storage<Entry<V>>, M: StateMachine<V>> Paxos<V, S, M> {
+    /// Create a server on top of `storage`, picking up whatever state a previous
+    /// run left in it. The snapshot and chosen values found there are replayed into
+    /// `state_machine`.
+    ///
+    /// `peers_id` are the acceptors the log starts with. Every server of a cluster,
+    /// including those added later on, must be given the same.
+    ///
+    /// The server does no I/O besides `storage`, and never reads the clock: the
+    /// current time is passed along with every input, starting with `now` here.
     pub fn new(
         local_id: usize,
         peers_id: HashSet<usize>,
-        tx: Tx<Outgoing>,
-        rx: Rx<Incoming>,
+        storage: S,
+        state_machine: M,
+        now: Instant,
+    ) -> Self {
+        let config = PaxosConfig::default();
+        Self::with_config(local_id, peers_id, storage, state_machine, config, now)
+    }
+
+    /// Like `new`, but tuned by `config` instead of the defaults.
+    pub fn with_config(
+        local_id: usize,
+        peers_id: HashSet<usize>,
+        storage: S,
+        state_machine: M,
+        config: PaxosConfig,
+        now: Instant,
     ) -> Self {
         // log!("Paxos start with peers_num: {:?}", peers_id);
-        Paxos {
+        let current_seq = storage
+            .current_seq()
+            .unwrap_or_else(|| SequenceNumber::new(local_id, 0));
+        let mut rng = match config.seed {
+            Some(seed) => SmallRng::seed_from_u64(seed),
+            None => SmallRng::from_entropy(),
+        };
+        let election_deadline = now + election_delay(&mut rng, config.election_timeout);
+        let mut configs = BTreeMap::new();
+        configs.insert(0, peers_id.into_iter().collect());
+        let mut paxos = Paxos {
             local_id,
-            last_promised: None,
-            chosen: None,
-            last_accepted_proposal: None,
-            peers_id,
-            proposal: None,
-            current_seq: SequenceNumber::new(local_id, 0),
-            tx,
-            rx,
+            configs,
+            storage,
+            proposals: BTreeMap::new(),
+            current_seq,
+            leadership: Leadership::new(config.heartbeat_interval, election_deadline),
+            state_machine,
+            sessions: Sessions::default(),
+            applied_upto: 0,
+            waiting: BTreeMap::new(),
+            batch: None,
+            reads: vec![],
+            snapshot_sent: BTreeMap::new(),
+            incoming_snapshot: None,
+            config,
+            rng,
+            effects: Effects::default(),
+        };
+        if let Some(snapshot) = paxos.storage.snapshot().cloned() {
+            if let Err(e) = paxos.restore(&snapshot) {
+                panic!("Server #{} failed to restore its snapshot: {}", local_id, e);
+            }
+        }
+        // We can't tell how much is left of a lease granted before a restart.
+        if let Some(holder) = paxos.storage.lease_holder() {
+            let until = now + paxos.config.lease_duration;
+            paxos.leadership.grant_lease(holder, until);
+        }
+        paxos.apply_chosen(now);
+        // Nobody is waiting to hear about the replay.
+        paxos.effects = Effects::default();
+        paxos
+    }
+
+    /// Lets tests look into the server's durable state.
+    #[cfg(test)]
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Lets tests see whether the server is the leader.
+    #[cfg(test)]
+    pub fn is_leader(&self) -> bool {
+        self.leadership.ballot().is_some()
+    }
+
+    /// Lets tests look into the state the log was applied to.
+    #[cfg(test)]
+    pub fn state_machine(&self) -> &M {
+        &self.state_machine
+    }
+
+    /// Make a state change durable. Nothing that depends on it may be sent before
+    /// this returns, so a storage failure takes the server down.
+    fn persist(&mut self, f: impl FnOnce(&mut S) -> io::Result<()>) {
+        if let Err(e) = f(&mut self.storage) {
+            panic!("Server #{} failed to persist state: {}", self.local_id, e);
+        }
+    }
+
+    /// Drive the server on tokio: feed it the datagrams coming from `rx`, fire its
+    /// timers every `TICK_INTERVAL`, and pass the datagrams it produces on to `tx`.
+    pub async fn run(mut self, tx: Tx<Outgoing<V, M::Output>>, mut rx: Rx<Incoming<V, M::Output>>) {
+        let mut ticker = time::interval(TICK_INTERVAL);
+        loop {
+            let effects = tokio::select! {
+                incoming = rx.next() => match incoming {
+                    Some(incoming) => self.handle_incoming(incoming, Instant::now()),
+                    None => break,
+                },
+                _ = ticker.tick() => self.tick(Instant::now()),
+            };
+            for line in effects.log {
+                log!("{}", line);
+            }
+            for outgoing in effects.outgoing {
+                tx.unbounded_send(outgoing).unwrap();
+            }
         }
     }
 
-    pub async fn run(mut self) {
-        while let Some(incoming) = self.rx.next().await {
-            self.handle_incoming(incoming);
+    /// Handle a datagram from `incoming.src`.
+    pub fn handle_incoming(
+        &mut self,
+        incoming: Incoming<V, M::Output>,
+        now: Instant,
+    ) -> Effects<V, M::Output> {
+        let Incoming { src, dgram } = incoming;
+        self.step(now, |paxos| match dgram {
+            Datagram::Request(req) => paxos.handle_request(src, req, now),
+            Datagram::Response(resp) => paxos.handle_response(src, resp, now),
+        })
+    }
+
+    /// Fire the timers that expired by `now`. Call it often enough for the timeouts
+    /// in `PaxosConfig` to make sense, e.g. every `TICK_INTERVAL`.
+    pub fn tick(&mut self, now: Instant) -> Effects<V, M::Output> {
+        self.step(now, |paxos| paxos.fire_timers(now))
+    }
+
+    /// Run `f`, answer the reads it made possible, and hand over everything produced.
+    fn step(&mut self, now: Instant, f: impl FnOnce(&mut Self)) -> Effects<V, M::Output> {
+        let leader = self.leadership.leader();
+        f(self);
+        self.serve_reads(now);
+        let new_leader = self.leadership.leader();
+        if new_leader != leader {
+            let event = Event::LeaderChanged { leader: new_leader };
+            self.effects.events.push(event);
         }
+        std::mem::take(&mut self.effects)
     }
 
     fn next_seq(&mut self) -> SequenceNumber {
         self.current_seq.increase();
-        self.current_seq
+        // A restarted proposer must never reuse a sequence number.
+        let seq = self.current_seq;
+        self.persist(|storage| storage.set_current_seq(seq));
+        seq
     }
 
-    fn handle_incoming(&mut self, incoming: Incoming) {
-        let Incoming { src, dgram } = incoming;
-        match dgram {
-            Datagram::Request(req) => self.handle_request(src, req),
-            Datagram::Response(resp) => self.handle_response(src, resp),
+    /// The acceptors of `instance`, unless the log isn't chosen far enough to tell.
+    fn members(&self, instance: usize) -> Option<&BTreeSet<usize>> {
+        if instance >= self.applied_upto + self.config.reconfig_window {
+            return None;
         }
+        self.configs
+            .range(..=instance)
+            .next_back()
+            .map(|(_, members)| members)
     }
 
-    fn handle_request(&mut self, src: usize, req: Request) {
-        log!(
-            "Server #{} handle req: {:?} from #{}.",
+    /// The acceptors of `instance` and how to count their votes, if `src` is one of them.
+    fn voters(
+        &self,
+        instance: usize,
+        src: usize,
+    ) -> Option<(BTreeSet<usize>, Arc<dyn QuorumSystem>)> {
+        self.members(instance)
+            .filter(|members| members.contains(&src))
+            .map(|members| (members.clone(), self.config.quorums.clone()))
+    }
+
+    /// Every server that's been an acceptor, which is who learns what's chosen.
+    fn everyone(&self) -> HashSet<usize> {
+        self.configs.values().flatten().copied().collect()
+    }
+
+    /// The highest sequence number we promised for any instance from `from` on.
+    fn highest_promise(&self, from: usize) -> Option<SequenceNumber> {
+        let last = self.storage.last_instance().unwrap_or(0);
+        (from..=last)
+            .filter_map(|instance| self.storage.promised(instance))
+            .chain(self.storage.leader_promise().map(|(_, seq)| seq))
+            .max()
+    }
+
+    /// Whether we know a value is chosen in `instance`, even if it's been compacted away.
+    fn is_chosen(&self, instance: usize) -> bool {
+        instance < self.applied_upto || self.storage.chosen(instance).is_some()
+    }
+
+    /// The first slot that is neither chosen nor being proposed by us.
+    fn next_free_instance(&self) -> usize {
+        (self.applied_upto..)
+            .find(|i| !self.proposals.contains_key(i) && !self.is_chosen(*i))
+            .unwrap()
+    }
+
+    fn first_unchosen_instance(&self) -> usize {
+        (self.applied_upto..).find(|i| !self.is_chosen(*i)).unwrap()
+    }
+
+    /// Where our log starts: everything below is only in the snapshot.
+    fn compacted(&self) -> usize {
+        self.storage.snapshot().map_or(0, |snapshot| snapshot.upto)
+    }
+
+    /// The highest sequence number promised for `instance`, either for the instance
+    /// alone or to a leader for every instance from some point on.
+    fn promised(&self, instance: usize) -> Option<SequenceNumber> {
+        let leader_promise = self
+            .storage
+            .leader_promise()
+            .filter(|&(from, _)| from <= instance)
+            .map(|(_, seq)| seq);
+        self.storage.promised(instance).max(leader_promise)
+    }
+
+    fn election_deadline(&mut self, now: Instant) -> Instant {
+        now + election_delay(&mut self.rng, self.config.election_timeout)
+    }
+
+    fn send(&mut self, dst: HashSet<usize>, dgram: Datagram<V, M::Output>) {
+        self.effects.outgoing.push(Outgoing { dst, dgram });
+    }
+
+    fn reply(&mut self, dst: usize, resp: Response<V, M::Output>) {
+        self.send((dst..dst + 1).collect(), Datagram::Response(resp));
+    }
+
+    fn forward(&mut self, leader: usize, id: RequestId, value: Entry<V>) {
+        trace!(
+            self,
+            "Server #{} forward `{:?}` to leader #{}.",
             self.local_id,
-            req,
-            src
+            value,
+            leader
         );
-        match req {
-            Request::Prepare { seq } => {
-                if self.last_promised.is_none() || self.last_promised.unwrap() <= seq {
-                    self.last_promised = Some(seq);
-                    let resp = Response::Prepare(self.last_accepted_proposal);
-                    self.tx
-                        .unbounded_send(Outgoing {
-                            dst: (src..src + 1).collect(),
-                            dgram: Datagram::Response(resp),
-                        })
-                        .unwrap();
+        let req = Request::Forward { id, value };
+        self.send((leader..leader + 1).collect(), Datagram::Request(req));
+    }
+
+    /// Send our snapshot to `dst`, which asked for something it covers, unless we
+    /// did lately: it may still be on its way, and it's big.
+    fn send_snapshot(&mut self, dst: usize, now: Instant) {
+        let interval = self.config.snapshot_resend_interval;
+        if self
+            .snapshot_sent
+            .get(&dst)
+            .is_some_and(|&sent| now < sent + interval)
+        {
+            return;
+        }
+        self.snapshot_sent.insert(dst, now);
+        let snapshot = self.storage.snapshot().unwrap();
+        let upto = snapshot.upto;
+        let encoded = match bincode::serialize(snapshot) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                trace!(
+                    self,
+                    "Server #{} can't encode snapshot: {}",
+                    self.local_id,
+                    e
+                );
+                return;
+            }
+        };
+        trace!(
+            self,
+            "Server #{} send snapshot up to instance {} to #{}, {} bytes.",
+            self.local_id,
+            upto,
+            dst,
+            encoded.len()
+        );
+        let size = self.config.snapshot_chunk_size.max(1);
+        for (i, data) in encoded.chunks(size).enumerate() {
+            let req = Request::InstallSnapshot {
+                upto,
+                offset: i * size,
+                len: encoded.len(),
+                data: data.to_vec(),
+            };
+            self.send((dst..dst + 1).collect(), Datagram::Request(req));
+        }
+    }
+
+    /// Add a chunk of the snapshot up to `upto` that `src` sends, and install the
+    /// snapshot once it's whole. Chunks are only taken in order: those that follow a
+    /// lost one are dropped, for the next time `src` sends the snapshot to fill in.
+    fn receive_snapshot(
+        &mut self,
+        src: usize,
+        upto: usize,
+        offset: usize,
+        len: usize,
+        data: Vec<u8>,
+        now: Instant,
+    ) {
+        if upto <= self.applied_upto {
+            return;
+        }
+        let mut incoming = match self.incoming_snapshot.take() {
+            Some(incoming) if (incoming.src, incoming.upto, incoming.len) == (src, upto, len) => {
+                incoming
+            }
+            // Whatever we were putting together, this one starts over.
+            _ if offset == 0 => IncomingSnapshot {
+                src,
+                upto,
+                len,
+                data: vec![],
+            },
+            other => {
+                self.incoming_snapshot = other;
+                return;
+            }
+        };
+        if offset == incoming.data.len() {
+            incoming.data.extend_from_slice(&data);
+        }
+        if incoming.data.len() < len {
+            self.incoming_snapshot = Some(incoming);
+            return;
+        }
+        match bincode::deserialize(&incoming.data) {
+            Ok(snapshot) => self.install_snapshot(snapshot, now),
+            Err(e) => trace!(
+                self,
+                "Server #{} can't decode snapshot: {}",
+                self.local_id,
+                e
+            ),
+        }
+    }
+
+    fn broadcast(&mut self, req: Request<V>) {
+        self.send(self.everyone(), Datagram::Request(req));
+    }
+
+    fn send_to_members(&mut self, instance: usize, req: Request<V>) {
+        let dst = self.members(instance).unwrap().iter().copied().collect();
+        self.send(dst, Datagram::Request(req));
+    }
+
+    /// Fire the proposers' expired timers, and those of the leader election.
+    fn fire_timers(&mut self, now: Instant) {
+        // Only members run for leader; anyone else is just a client.
+        let current = self.first_unchosen_instance();
+        let member = self
+            .members(current)
+            .is_some_and(|members| members.contains(&self.local_id));
+        if member && self.leadership.election_due(now) {
+            self.start_election(now);
+        }
+        // Our promises don't hold with the acceptors that just took over.
+        if let (Some(_), Some(from)) = (self.leadership.ballot(), self.leadership.from()) {
+            if self.members(current) != self.members(from) {
+                if member {
+                    trace!(
+                        self,
+                        "Server #{} runs again for the new members.",
+                        self.local_id
+                    );
+                    self.start_election(now);
                 } else {
-                    log!(
-                        "Server#{} ignore low-seq req `{:?}` from #{}",
-                        self.local_id,
-                        req,
-                        src
+                    trace!(
+                        self,
+                        "Server #{} steps down, no longer a member.",
+                        self.local_id
                     );
+                    let deadline = self.election_deadline(now);
+                    self.leadership.step_down(deadline);
                 }
             }
-            Request::Accept { seq, value } => {
-                if self.last_promised.is_none() || self.last_promised.unwrap() <= seq {
-                    self.last_accepted_proposal = Some(AcceptedProposal::new(seq, value));
-                    let resp = Response::Accept { seq };
-                    self.tx
-                        .unbounded_send(Outgoing {
-                            dst: (src..src + 1).collect(),
-                            dgram: Datagram::Response(resp),
-                        })
-                        .unwrap();
-                } else {
-                    log!(
-                        "Server#{} ignore req `{:?}` from #{}",
+        }
+        if self.leadership.heartbeat_due(now) {
+            let seq = self.leadership.ballot().unwrap();
+            let chosen_upto = self.first_unchosen_instance();
+            let beat = self.leadership.beat();
+            self.broadcast(Request::Heartbeat {
+                seq,
+                chosen_upto,
+                beat,
+            });
+        }
+
+        if self
+            .batch
+            .as_ref()
+            .is_some_and(|batch| batch.deadline <= now)
+        {
+            self.flush_batch(now);
+        }
+        let expired: Vec<usize> = self
+            .proposals
+            .iter()
+            .filter(|(_, proposal)| proposal.timer.deadline() <= now)
+            .map(|(&instance, _)| instance)
+            .collect();
+        for instance in expired {
+            match self.proposals[&instance].timer {
+                Timer::Retry(_) => self.start_round(instance, now),
+                Timer::Timeout(_) => {
+                    trace!(
+                        self,
+                        "Server #{} round for instance {} timed out.",
                         self.local_id,
-                        req,
-                        src
+                        instance
                     );
+                    self.abandon_round(instance, now, true);
                 }
             }
-            Request::Learn { value } => {
-                if let Some(chosen_value) = self.chosen {
-                    assert!(chosen_value == value);
-                }
-                self.chosen = Some(value);
-                log!("Server#{} learned {}", self.local_id, self.chosen.unwrap());
-            }
-            Request::Propose { value } => {
-                if let Some(proposal) = &self.proposal {
-                    if proposal.wanted_value == value {
-                        log!("Retry to propose `{}`", value);
-                    } else {
-                        log!(
-                            "Override a existed proposal value `{}`.",
-                            proposal.wanted_value
-                        );
-                    }
-                }
-                let seq = self.next_seq();
-                self.proposal = Some(Proposal {
-                    seq,
-                    value: None,
-                    wanted_value: value,
-                    highest_seq: None,
-                    prepared: HashSet::new(),
-                    accepted: HashSet::new(),
-                });
-                let req = Request::Prepare { seq };
-                self.tx
-                    .unbounded_send(Outgoing {
-                        dst: self.peers_id.clone(),
-                        dgram: Datagram::Request(req),
-                    })
-                    .unwrap();
-            }
-            Request::Query => {
-                let resp = Response::Query { val: self.chosen };
-                self.tx
-                    .unbounded_send(Outgoing {
-                        dst: (src..src + 1).collect(),
-                        dgram: Datagram::Response(resp),
-                    })
-                    .unwrap();
-            }
-        }
-    }
-
-    fn handle_response(&mut self, src: usize, resp: Response) {
-        log!(
-            "Server #{} handle resp: {:?} from #{}.",
+        }
+    }
+
+    fn start_election(&mut self, now: Instant) {
+        let seq = self.next_seq();
+        let from = self.first_unchosen_instance();
+        trace!(
+            self,
+            "Server #{} run for leader with {:?} from instance {}.",
             self.local_id,
-            resp,
-            src
+            seq,
+            from
         );
-        match resp {
-            Response::Prepare(accepted_proposal) => {
-                if let Some(ref mut proposal) = self.proposal {
-                    proposal.prepared.insert(src);
-                    if let Some(AcceptedProposal { seq, val }) = accepted_proposal {
-                        if proposal.prepared.len() <= self.peers_id.len() / 2 + 1
-                            && seq >= *proposal.highest_seq.get_or_insert(seq)
-                        {
-                            proposal.value = Some(val);
-                        }
-                    }
-                    if proposal.prepared.len() == self.peers_id.len() / 2 + 1 {
-                        let req = Request::Accept {
-                            seq: proposal.seq,
-                            value: *proposal.value.get_or_insert(proposal.wanted_value),
-                        };
-                        self.tx
-                            .unbounded_send(Outgoing {
-                                dst: proposal.prepared.clone(),
-                                dgram: Datagram::Request(req),
-                            })
-                            .unwrap();
+        let deadline = self.election_deadline(now);
+        self.leadership.start_election(seq, from, deadline);
+        self.send_to_members(from, Request::Elect { from, seq });
+    }
+
+    /// We just won the election described by `candidacy`. Finish what the previous
+    /// leaders left behind, then carry on with our own proposals in phase 2 only.
+    fn take_over(&mut self, candidacy: Candidacy<Entry<V>>, now: Instant) {
+        trace!(
+            self,
+            "Server #{} becomes leader with {:?}.",
+            self.local_id,
+            candidacy.seq
+        );
+        let last_accepted = candidacy.accepted.keys().next_back().copied();
+        let mut displaced = vec![];
+        for (instance, accepted) in candidacy.accepted {
+            if self.is_chosen(instance) {
+                continue;
+            }
+            // The accepted value may already be chosen, so it's the only one we may
+            // propose here. Ours has to move elsewhere.
+            let clients = match self.proposals.remove(&instance) {
+                Some(proposal) if proposal.wanted_value == accepted.val => proposal.clients,
+                Some(proposal) => {
+                    if !proposal.clients.is_empty() {
+                        displaced.push((proposal.clients, proposal.wanted_value));
                     }
-                } else {
-                    panic!("recv a resp for prepare, but no proposal presented");
+                    vec![]
+                }
+                None => vec![],
+            };
+            self.insert_proposal(instance, clients, accepted.val, now);
+        }
+        // Fill the holes, or the log can't be applied past them.
+        if let Some(last) = last_accepted {
+            for instance in candidacy.from..last {
+                if !self.proposals.contains_key(&instance) && !self.is_chosen(instance) {
+                    self.insert_proposal(instance, vec![], Entry::Noop, now);
                 }
             }
-            Response::Accept { seq } => {
-                // log!("handle accept resp seq: {}", seq);
-                if let Some(ref mut proposal) = self.proposal {
-                    if seq == proposal.seq {
-                        proposal.accepted.insert(src);
-                        if proposal.accepted.len() == 1 + self.peers_id.len() / 2 {
-                            assert!(proposal.value.is_some());
-                            let value = proposal.value.unwrap();
-                            if value == proposal.wanted_value {
-                                log!("proposal value `{}` success.", value);
-                            } else {
-                                log!(
-                                    "proposal value `{}` fail, `{}` is chosen.",
-                                    proposal.wanted_value,
-                                    value
-                                );
-                            }
-                            let req = Request::Learn {
-                                value: proposal.value.unwrap(),
-                            };
-                            log!("value accepted by majority: {}", value);
-                            self.tx
-                                .unbounded_send(Outgoing {
-                                    dst: self.peers_id.clone(),
-                                    dgram: Datagram::Request(req),
-                                })
-                                .unwrap();
-                        }
-                    }
-                } else {
-                    panic!("recv an accepted response, but no proposal presented")
+        }
+        // No acceptor in the quorum has accepted anything in the remaining slots,
+        // so our in-flight proposals are free to go ahead with the new ballot.
+        let instances: Vec<usize> = self.proposals.keys().copied().collect();
+        for instance in instances {
+            self.start_round(instance, now);
+        }
+        for (clients, value) in displaced {
+            self.propose(clients, value, now);
+        }
+    }
+
+    /// The current round of the proposal for `instance` can't succeed. Schedule a new
+    /// one, right away or after a random backoff, unless we are out of attempts.
+    fn abandon_round(&mut self, instance: usize, now: Instant, backoff: bool) {
+        let attempts = self.proposals[&instance].attempts;
+        if attempts >= self.config.max_attempts {
+            let delay = self.backoff(1);
+            let leading = self.leadership.ballot() == Some(self.proposals[&instance].seq);
+            let proposal = self.proposals.get_mut(&instance).unwrap();
+            trace!(
+                self,
+                "Server #{} give up proposing `{:?}` after {} attempts.",
+                self.local_id,
+                proposal.wanted_value,
+                attempts
+            );
+            let clients = std::mem::take(&mut proposal.clients);
+            let value = proposal.wanted_value.clone();
+            if leading {
+                // Acceptors may have the value under our ballot, which mustn't carry
+                // another one in this instance. Keep at it, for nobody.
+                proposal.attempts = 0;
+                proposal.timer = Timer::Retry(now + delay);
+            } else {
+                self.proposals.remove(&instance);
+            }
+            // Some acceptors may have accepted it, for a later leader to choose.
+            self.report(&clients, value, ProposeResult::Unknown { instance });
+        } else if backoff {
+            let delay = self.backoff(attempts);
+            trace!(
+                self,
+                "Server #{} retry instance {} in {:?}.",
+                self.local_id,
+                instance,
+                delay
+            );
+            self.proposals.get_mut(&instance).unwrap().timer = Timer::Retry(now + delay);
+        } else {
+            self.start_round(instance, now);
+        }
+    }
+
+    /// Tell the clients that made the calls `clients` what became of their part of
+    /// the `value` they wanted chosen.
+    fn report(&mut self, clients: &[RequestId], value: Entry<V>, result: ProposeResult<M::Output>) {
+        let commands = match value {
+            Entry::Value { id, value } => vec![(id, value)],
+            Entry::Batch(commands) => commands,
+            Entry::Reconfigure(members) => {
+                for &id in clients {
+                    let resp = Response::Reconfigure {
+                        id,
+                        members: members.clone(),
+                        result: result.clone().map(|_| ()),
+                    };
+                    self.reply(id.client, resp);
                 }
+                return;
             }
-            Response::Query { val } => {
-                if let Some(val) = val {
-                    log!("Server #{} Answer: {}.", src, val);
-                } else {
-                    log!("Server #{} Answer: not learn yet.", src);
+            Entry::Noop => return,
+        };
+        for (id, value) in commands {
+            if clients.contains(&id) {
+                let result = result.clone();
+                self.reply(id.client, Response::Propose { id, value, result });
+            }
+        }
+    }
+
+    /// Make sure our next sequence number is higher than `seq`.
+    fn observe_seq(&mut self, seq: SequenceNumber) {
+        if seq.seq > self.current_seq.seq {
+            self.current_seq.seq = seq.seq;
+        }
+    }
+
+    /// A random delay, with an upper bound growing exponentially in `attempts`,
+    /// so that duelling proposers fall out of step.
+    fn backoff(&mut self, attempts: usize) -> Duration {
+        let exp = attempts.saturating_sub(1).min(16) as u32;
+        let max = self
+            .config
+            .backoff_base
+            .checked_mul(1 << exp)
+            .map_or(self.config.backoff_max, |d| d.min(self.config.backoff_max));
+        max.mul_f64(self.rng.gen::<f64>())
+    }
+
+    /// Handle a value a client wants chosen: pass it on to the leader if there is
+    /// another one, or propose it ourselves.
+    fn submit(&mut self, id: RequestId, value: Entry<V>, now: Instant) {
+        if self.handled(id, &value) {
+            return;
+        }
+        match self.leadership.leader() {
+            Some(leader) if leader != self.local_id => self.forward(leader, id, value),
+            _ => self.enqueue(id, value, now),
+        }
+    }
+
+    /// Propose the `value` of the call `id`, or hold it back to go with others in a batch.
+    fn enqueue(&mut self, id: RequestId, value: Entry<V>, now: Instant) {
+        let window = self.config.batch_window;
+        let command = match value {
+            Entry::Value { id, value } if window > Duration::from_millis(0) => (id, value),
+            value => return self.propose(vec![id], value, now),
+        };
+        let batch = self.batch.get_or_insert_with(|| PendingBatch {
+            commands: vec![],
+            deadline: now + window,
+        });
+        batch.commands.push(command);
+        if batch.commands.len() >= self.config.max_batch {
+            self.flush_batch(now);
+        }
+    }
+
+    /// Propose the commands held back, in a single instance.
+    fn flush_batch(&mut self, now: Instant) {
+        let mut commands = match self.batch.take() {
+            Some(batch) => batch.commands,
+            None => return,
+        };
+        let clients = commands.iter().map(|&(id, _)| id).collect();
+        let value = if commands.len() == 1 {
+            let (id, value) = commands.pop().unwrap();
+            Entry::Value { id, value }
+        } else {
+            trace!(
+                self,
+                "Server #{} propose a batch of {} commands.",
+                self.local_id,
+                commands.len()
+            );
+            Entry::Batch(commands)
+        };
+        self.propose(clients, value, now);
+    }
+
+    /// Whether the call `id` for `value` is taken care of already: we're proposing
+    /// it, or it took effect, in which case the client is answered like the first time.
+    fn handled(&mut self, id: RequestId, value: &Entry<V>) -> bool {
+        let batched = self
+            .batch
+            .as_ref()
+            .is_some_and(|batch| batch.commands.iter().any(|&(batched, _)| batched == id));
+        let proposing = batched
+            || self.proposals.values().any(|p| p.clients.contains(&id))
+            || self.waiting.values().any(|waiting| waiting.contains(&id));
+        if proposing {
+            trace!(
+                self,
+                "Server #{} already proposing for {:?}.",
+                self.local_id,
+                id
+            );
+            return true;
+        }
+        if let Entry::Value { .. } = value {
+            match self.sessions.seen(id) {
+                Seen::New => {}
+                Seen::Last(session) => {
+                    let result = ProposeResult::Chosen {
+                        instance: session.instance,
+                        output: session.output.clone(),
+                    };
+                    self.report(&[id], value.clone(), result);
+                    return true;
+                }
+                Seen::Old => {
+                    trace!(self, "Server #{} drop leftover {:?}.", self.local_id, id);
+                    return true;
                 }
             }
         }
+        false
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    /// Handle a client's query of `instance`: answer right away if a stale read will
+    /// do, or else pass it on to the leader if there is another one.
+    fn query(
+        &mut self,
+        id: RequestId,
+        instance: usize,
+        consistency: ReadConsistency,
+        now: Instant,
+    ) {
+        match (consistency, self.leadership.leader()) {
+            (ReadConsistency::Stale, _) => self.answer(id, instance),
+            (_, Some(leader)) if leader != self.local_id => {
+                trace!(
+                    self,
+                    "Server #{} forward query of instance {} to leader #{}.",
+                    self.local_id,
+                    instance,
+                    leader
+                );
+                let req = Request::ForwardQuery {
+                    id,
+                    instance,
+                    consistency,
+                };
+                self.send((leader..leader + 1).collect(), Datagram::Request(req));
+            }
+            _ => self.read(id, instance, consistency, now),
+        }
+    }
 
-    // #[test]
-    // fn test_encode() {
-    //     let dgram = Datagram::Request(Request::Propose{value: 42});
-    //     let encoded = dgram.encode();
-    //     let decoded: Datagram = bincode::deserialize(&encoded[8..]).unwrap();
-    //     if let Datagram::Request(Request::Propose{ value: 42 }) = decoded {
-    //         //..
-    //     }
-    //     else{
+    /// Answer a linearizable query as the leader, once our log holds everything
+    /// chosen before it arrived: it has to be applied up to where our proposals
+    /// reach, and nobody else may have had anything chosen meanwhile, which the lease
+    /// or the next heartbeat makes sure of.
+    fn read(&mut self, id: RequestId, instance: usize, consistency: ReadConsistency, now: Instant) {
+        if self.leadership.ballot().is_none() {
+            self.reply(id.client, Response::QueryFailed { id, instance });
+            return;
+        }
+        let beat = if consistency == ReadConsistency::Lease && self.leadership.has_lease(now) {
+            None
+        } else {
+            // Acknowledgements of heartbeats sent before the query came may be outdated.
+            self.leadership.hasten_heartbeat(now);
+            Some(self.leadership.beat() + 1)
+        };
+        let read_index = self
+            .proposals
+            .keys()
+            .next_back()
+            .map_or(0, |&instance| instance + 1)
+            .max(self.first_unchosen_instance());
+        self.reads.push(PendingRead {
+            id,
+            instance,
+            read_index,
+            beat,
+            deadline: now + self.config.proposal_timeout,
+        });
+    }
+
+    /// Answer the queries that may be, and fail those that never will.
+    fn serve_reads(&mut self, now: Instant) {
+        if self.reads.is_empty() {
+            return;
+        }
+        let leading = self.leadership.ballot().is_some();
+        let confirmed = self.leadership.confirmed();
+        // Quorums of the acceptors of the next instance may not cover the ones after a
+        // reconfiguration, so wait for it to take effect.
+        let settled = self
+            .configs
+            .keys()
+            .next_back()
+            .is_some_and(|&from| from <= self.applied_upto);
+        for read in std::mem::take(&mut self.reads) {
+            let instance = read.instance;
+            let ready = settled
+                && self.applied_upto >= read.read_index
+                && read.beat.is_none_or(|beat| beat <= confirmed);
+            if ready {
+                self.answer(read.id, instance);
+            } else if !leading || now >= read.deadline {
+                let id = read.id;
+                self.reply(id.client, Response::QueryFailed { id, instance });
+            } else {
+                self.reads.push(read);
+            }
+        }
+    }
+
+    /// Answer the call `id` with what our log holds in `instance`, unless it's been
+    /// compacted away.
+    fn answer(&mut self, id: RequestId, instance: usize) {
+        if instance < self.compacted() {
+            self.reply(id.client, Response::QueryFailed { id, instance });
+        } else {
+            let val = self.storage.chosen(instance);
+            self.reply(id.client, Response::Query { id, instance, val });
+        }
+    }
+
+    fn propose(&mut self, clients: Vec<RequestId>, value: Entry<V>, now: Instant) {
+        let instance = self.next_free_instance();
+        self.insert_proposal(instance, clients, value, now);
+        self.start_round(instance, now);
+    }
+
+    fn insert_proposal(
+        &mut self,
+        instance: usize,
+        clients: Vec<RequestId>,
+        value: Entry<V>,
+        now: Instant,
+    ) {
+        self.proposals.insert(
+            instance,
+            Proposal {
+                clients,
+                seq: self.current_seq,
+                value: None,
+                wanted_value: value,
+                highest_seq: None,
+                prepared: BTreeSet::new(),
+                accepted: BTreeSet::new(),
+                attempts: 0,
+                timer: Timer::Retry(now),
+            },
+        );
+    }
+
+    /// Start a new round of the proposal for `instance`. The leader goes straight to
+    /// phase 2 with its ballot; anyone else runs phase 1 with a fresh sequence
+    /// number, unless there's a leader to leave the instance to.
+    fn start_round(&mut self, instance: usize, now: Instant) {
+        if let Some(leader) = self.leadership.leader().filter(|&id| id != self.local_id) {
+            let proposal = self.proposals.remove(&instance).unwrap();
+            match proposal.wanted_value {
+                // The leader makes batches of its own.
+                Entry::Batch(commands) => {
+                    for (id, value) in commands {
+                        self.forward(leader, id, Entry::Value { id, value });
+                    }
+                }
+                // Every call waiting for it gets its answer from the leader.
+                value => {
+                    for id in proposal.clients {
+                        self.forward(leader, id, value.clone());
+                    }
+                }
+            }
+            return;
+        }
+        if self.members(instance).is_none() {
+            // Wait for the log to be chosen far enough to know who the acceptors are.
+            let retry = now + self.config.backoff_base;
+            self.proposals.get_mut(&instance).unwrap().timer = Timer::Retry(retry);
+            return;
+        }
+        // The promises we were elected with only hold where the acceptors are the same.
+        let ballot = self.leadership.ballot().filter(|_| {
+            let from = self.leadership.from().unwrap();
+            self.members(instance) == self.members(from)
+        });
+        let seq = match ballot {
+            Some(seq) => seq,
+            None => self.next_seq(),
+        };
+        let timeout = self.config.proposal_timeout;
+        let proposal = self.proposals.get_mut(&instance).unwrap();
+        proposal.seq = seq;
+        proposal.value = None;
+        proposal.highest_seq = None;
+        proposal.prepared.clear();
+        proposal.accepted.clear();
+        proposal.attempts += 1;
+        proposal.timer = Timer::Timeout(now + timeout);
+        if ballot.is_some() {
+            let value = proposal.wanted_value.clone();
+            proposal.value = Some(value.clone());
+            self.send_to_members(
+                instance,
+                Request::Accept {
+                    instance,
+                    seq,
+                    value,
+                },
+            );
+        } else {
+            self.send_to_members(instance, Request::Prepare { instance, seq });
+        }
+    }
+
+    /// `value` is known to be chosen in `instance`. If that's what we were proposing
+    /// there, the client gets its output once the instance is applied; otherwise move
+    /// our value to another slot.
+    fn settle(&mut self, instance: usize, value: &Entry<V>, now: Instant) {
+        let proposal = match self.proposals.remove(&instance) {
+            Some(proposal) => proposal,
+            None => return,
+        };
+        let clients = proposal.clients;
+        let wanted_value = match proposal.wanted_value {
+            _ if clients.is_empty() => return,
+            Entry::Noop => return,
+            wanted_value => wanted_value,
+        };
+        if *value == wanted_value {
+            trace!(
+                self,
+                "proposal value `{:?}` success at instance {}.",
+                value,
+                instance
+            );
+            self.waiting.insert(instance, clients);
+        } else {
+            trace!(
+                self,
+                "proposal value `{:?}` fail, `{:?}` is chosen at instance {}.",
+                wanted_value,
+                value,
+                instance
+            );
+            // Keep trying in the next free slot.
+            self.propose(clients, wanted_value, now);
+        }
+    }
+
+    /// Apply the chosen values to the state machine in slot order, up to the first
+    /// hole in the log, and send the outputs to the clients waiting for them.
+    fn apply_chosen(&mut self, now: Instant) {
+        while let Some(entry) = self.storage.chosen(self.applied_upto) {
+            let instance = self.applied_upto;
+            self.applied_upto += 1;
+            let commands = match entry {
+                Entry::Value { id, value } => vec![(id, value)],
+                Entry::Batch(commands) => commands,
+                Entry::Noop => continue,
+                Entry::Reconfigure(members) => {
+                    self.reconfigure(instance, members, now);
+                    continue;
+                }
+            };
+            let waiting = self.waiting.remove(&instance).unwrap_or_default();
+            for (id, value) in commands {
+                let result = self.apply_command(instance, id, value.clone());
+                if let Some(result) = result {
+                    self.report(&waiting, Entry::Value { id, value }, result);
+                }
+            }
+            self.sessions.expire(instance, self.config.session_timeout);
+        }
+        if let Some(interval) = self.config.snapshot_interval {
+            if self.applied_upto >= self.compacted() + interval.max(1) {
+                self.compact();
+            }
+        }
+    }
+
+    /// Apply the command of the call `id`, chosen in `instance`, unless it took
+    /// effect already. Returns what to tell its client, if it may still be waiting.
+    fn apply_command(
+        &mut self,
+        instance: usize,
+        id: RequestId,
+        value: V,
+    ) -> Option<ProposeResult<M::Output>> {
+        match self.sessions.seen(id) {
+            Seen::New => {
+                let output = self.state_machine.apply(value.clone());
+                self.sessions.record(id, instance, output.clone());
+                let event = Event::Applied {
+                    instance,
+                    value,
+                    output: output.clone(),
+                };
+                self.effects.events.push(event);
+                Some(ProposeResult::Chosen { instance, output })
+            }
+            // Chosen again for a retry: it took effect the first time.
+            Seen::Last(session) => Some(ProposeResult::Chosen {
+                instance: session.instance,
+                output: session.output.clone(),
+            }),
+            Seen::Old => None,
+        }
+    }
+
+    /// Snapshot the state machine, and drop the log it stands in for.
+    fn compact(&mut self) {
+        trace!(
+            self,
+            "Server #{} snapshot up to instance {}.",
+            self.local_id,
+            self.applied_upto
+        );
+        let encoded = bincode::serialize(&self.state_machine.snapshot()).and_then(|state| {
+            let sessions = bincode::serialize(&self.sessions)?;
+            Ok((state, sessions))
+        });
+        let snapshot = encoded
+            .map_err(io::Error::other)
+            .map(|(state, sessions)| Snapshot {
+                upto: self.applied_upto,
+                configs: self.configs.clone(),
+                state,
+                sessions,
+            });
+        self.persist(|storage| storage.set_snapshot(snapshot?));
+    }
+
+    /// Take the state the log leads to below `snapshot.upto` from `snapshot`.
+    fn restore(&mut self, snapshot: &Snapshot) -> bincode::Result<()> {
+        let state = bincode::deserialize(&snapshot.state)?;
+        self.sessions = bincode::deserialize(&snapshot.sessions)?;
+        self.state_machine.restore(state);
+        self.configs = snapshot.configs.clone();
+        self.applied_upto = snapshot.upto;
+        Ok(())
+    }
+
+    /// Catch up with `snapshot`, taken by a server further along than us.
+    fn install_snapshot(&mut self, snapshot: Snapshot, now: Instant) {
+        let upto = snapshot.upto;
+        if upto <= self.applied_upto {
+            return;
+        }
+        trace!(
+            self,
+            "Server #{} install snapshot up to instance {}.",
+            self.local_id,
+            upto
+        );
+        if let Err(e) = self.restore(&snapshot) {
+            trace!(
+                self,
+                "Server #{} can't decode snapshot: {}",
+                self.local_id,
+                e
+            );
+            return;
+        }
+        // Nothing tells us any more what was chosen in the slots the snapshot covers,
+        // but the sessions know which calls took effect there.
+        let waiting = self.waiting.split_off(&upto);
+        for (instance, clients) in std::mem::replace(&mut self.waiting, waiting) {
+            if let Some(value) = self.storage.chosen(instance) {
+                for id in clients {
+                    let result = self.outcome(id, instance);
+                    self.report(&[id], value.clone(), result);
+                }
+            }
+        }
+        let lost: Vec<usize> = self.proposals.range(..upto).map(|(&i, _)| i).collect();
+        for instance in lost {
+            let proposal = self.proposals.remove(&instance).unwrap();
+            for &id in &proposal.clients {
+                let result = self.outcome(id, instance);
+                self.report(&[id], proposal.wanted_value.clone(), result);
+            }
+        }
+        self.persist(|storage| storage.set_snapshot(snapshot));
+        self.effects.events.push(Event::SnapshotInstalled { upto });
+        self.apply_chosen(now);
+    }
+
+    /// What became of the call `id`, which may have been chosen in `instance`, after
+    /// the log there was compacted away.
+    fn outcome(&self, id: RequestId, instance: usize) -> ProposeResult<M::Output> {
+        match self.sessions.seen(id) {
+            Seen::Last(session) => ProposeResult::Chosen {
+                instance: session.instance,
+                output: session.output.clone(),
+            },
+            Seen::New | Seen::Old => ProposeResult::Unknown { instance },
+        }
+    }
+
+    /// `members`, chosen in `instance`, take over after the window.
+    fn reconfigure(&mut self, instance: usize, members: BTreeSet<usize>, now: Instant) {
+        let from = instance + self.config.reconfig_window;
+        trace!(
+            self,
+            "Server #{} members from instance {}: {:?}",
+            self.local_id,
+            from,
+            members
+        );
+        self.configs.insert(from, members.clone());
+        // Fill the window with no-ops, so the new members don't have to wait for
+        // clients to get there.
+        if self.leadership.ballot().is_some() {
+            while self.next_free_instance() < from {
+                self.propose(vec![], Entry::Noop, now);
+            }
+        }
+        for id in self.waiting.remove(&instance).unwrap_or_default() {
+            let resp = Response::Reconfigure {
+                id,
+                members: members.clone(),
+                result: ProposeResult::Chosen {
+                    instance,
+                    output: (),
+                },
+            };
+            self.reply(id.client, resp);
+        }
+        let event = Event::Reconfigured {
+            instance: from,
+            members,
+        };
+        self.effects.events.push(event);
+    }
+
+    fn handle_request(&mut self, src: usize, req: Request<V>, now: Instant) {
+        trace!(
+            self,
+            "Server #{} handle req: {:?} from #{}.",
+            self.local_id,
+            req,
+            src
+        );
+        match req {
+            Request::Prepare { instance, seq } => match self.promised(instance) {
+                // Whatever we accepted there is gone, but it's chosen anyway.
+                _ if instance < self.compacted() => self.send_snapshot(src, now),
+                Some(promised) if promised > seq => {
+                    trace!(
+                        self,
+                        "Server#{} reject low-seq req `{:?}` from #{}",
+                        self.local_id,
+                        req,
+                        src
+                    );
+                    let resp = Response::PrepareRejected {
+                        instance,
+                        seq,
+                        promised,
+                    };
+                    self.reply(src, resp);
+                }
+                _ if self.leadership.lease_holder(seq, now).is_some() => {
+                    trace!(
+                        self,
+                        "Server#{} ignore `{:?}` while leased.",
+                        self.local_id,
+                        req
+                    );
+                }
+                _ => {
+                    self.persist(|storage| storage.set_promised(instance, seq));
+                    let accepted = self.storage.accepted(instance);
+                    let resp = Response::Prepare {
+                        instance,
+                        seq,
+                        accepted,
+                    };
+                    self.reply(src, resp);
+                }
+            },
+            Request::Accept {
+                instance,
+                seq,
+                ref value,
+            } => match self.promised(instance) {
+                _ if instance < self.compacted() => self.send_snapshot(src, now),
+                Some(promised) if promised > seq => {
+                    trace!(
+                        self,
+                        "Server#{} reject req `{:?}` from #{}",
+                        self.local_id,
+                        req,
+                        src
+                    );
+                    let resp = Response::AcceptRejected {
+                        instance,
+                        seq,
+                        promised,
+                    };
+                    self.reply(src, resp);
+                }
+                _ => {
+                    let proposal = AcceptedProposal::new(seq, value.clone());
+                    self.persist(|storage| storage.set_accepted(instance, proposal));
+                    self.reply(src, Response::Accept { instance, seq });
+                }
+            },
+            // Already part of our snapshot.
+            Request::Learn { instance, .. } if instance < self.compacted() => {}
+            Request::Learn { instance, value } => {
+                trace!(
+                    self,
+                    "Server#{} learned {:?} at instance {}",
+                    self.local_id,
+                    value,
+                    instance
+                );
+                if let Some(chosen_value) = self.storage.chosen(instance) {
+                    assert!(chosen_value == value);
+                } else {
+                    let chosen = value.clone();
+                    self.persist(|storage| storage.set_chosen(instance, chosen));
+                }
+                self.settle(instance, &value, now);
+                self.apply_chosen(now);
+            }
+            Request::Propose { id, value } => self.submit(id, Entry::Value { id, value }, now),
+            Request::Reconfigure { id, members } => {
+                if members.is_empty() {
+                    trace!(
+                        self,
+                        "Server #{} refuse to leave no members.",
+                        self.local_id
+                    );
+                } else {
+                    self.submit(id, Entry::Reconfigure(members), now);
+                }
+            }
+            // Never forwarded again, so servers that disagree on the leader can't
+            // bounce a value between them.
+            Request::Forward { id, value } => {
+                if !self.handled(id, &value) {
+                    self.enqueue(id, value, now);
+                }
+            }
+            Request::Query {
+                id,
+                instance,
+                consistency,
+            } => self.query(id, instance, consistency, now),
+            // Never forwarded again, like `Forward`.
+            Request::ForwardQuery {
+                id,
+                instance,
+                consistency,
+            } => self.read(id, instance, consistency, now),
+            Request::Elect { from, seq } => {
+                let last = self.storage.last_instance();
+                let leader_promise = self.storage.leader_promise();
+                match (
+                    self.highest_promise(from),
+                    self.leadership.lease_holder(seq, now),
+                ) {
+                    // We can't report what we accepted below our snapshot.
+                    _ if from < self.compacted() => self.send_snapshot(src, now),
+                    (Some(promised), _) if promised > seq => {
+                        trace!(
+                            self,
+                            "Server#{} reject candidate `{:?}` from #{}",
+                            self.local_id,
+                            seq,
+                            src
+                        );
+                        self.reply(src, Response::ElectRejected { seq, promised });
+                    }
+                    // Otherwise the candidate would ignore the heartbeats of the lower
+                    // numbered leader and keep running in vain.
+                    (_, Some(promised)) => {
+                        trace!(
+                            self,
+                            "Server#{} reject candidate #{} while leased.",
+                            self.local_id,
+                            src
+                        );
+                        self.reply(src, Response::ElectRejected { seq, promised });
+                    }
+                    _ => {
+                        // Promising a higher number to more instances than before
+                        // can't break the old promise.
+                        let from_all = leader_promise.map_or(from, |(old, _)| old.min(from));
+                        self.persist(|storage| storage.set_leader_promise(from_all, seq));
+                        let accepted = (from..=last.unwrap_or(0))
+                            .filter_map(|instance| {
+                                self.storage.accepted(instance).map(|a| (instance, a))
+                            })
+                            .collect();
+                        self.reply(src, Response::Elect { seq, accepted });
+                    }
+                }
+            }
+            Request::Heartbeat {
+                seq,
+                chosen_upto,
+                beat,
+            } => {
+                self.observe_seq(seq);
+                let deadline = self.election_deadline(now);
+                if self.leadership.on_heartbeat(seq, deadline) {
+                    trace!(
+                        self,
+                        "Server #{} follows leader #{}.",
+                        self.local_id,
+                        seq.server_id
+                    );
+                }
+                // We missed some `Learn`s and can't apply anything past the hole.
+                if self.leadership.leader() == Some(src) && self.applied_upto < chosen_upto {
+                    let req = Request::CatchUp {
+                        from: self.applied_upto,
+                    };
+                    self.send((src..src + 1).collect(), Datagram::Request(req));
+                }
+                // Nothing we promised stands in the leader's way, and nothing will
+                // for a while.
+                let free = self.highest_promise(chosen_upto) <= Some(seq);
+                if self.leadership.leader() == Some(src) && free {
+                    if self.storage.lease_holder() != Some(seq) {
+                        self.persist(|storage| storage.set_lease_holder(seq));
+                    }
+                    let until = now + self.config.lease_duration;
+                    self.leadership.grant_lease(seq, until);
+                    self.reply(src, Response::Heartbeat { seq, beat });
+                }
+            }
+            Request::CatchUp { from } => {
+                let compacted = self.compacted();
+                if from < compacted {
+                    self.send_snapshot(src, now);
+                }
+                let dst: HashSet<usize> = (src..src + 1).collect();
+                for instance in from.max(compacted).. {
+                    let value = match self.storage.chosen(instance) {
+                        Some(value) => value,
+                        None => break,
+                    };
+                    let req = Request::Learn { instance, value };
+                    self.send(dst.clone(), Datagram::Request(req));
+                }
+            }
+            Request::InstallSnapshot {
+                upto,
+                offset,
+                len,
+                data,
+            } => self.receive_snapshot(src, upto, offset, len, data, now),
+            Request::Leader { id } => {
+                let leader = self.leadership.leader();
+                self.reply(id.client, Response::Leader { id, leader });
+            }
+        }
+    }
+
+    fn handle_response(&mut self, src: usize, resp: Response<V, M::Output>, now: Instant) {
+        trace!(
+            self,
+            "Server #{} handle resp: {:?} from #{}.",
+            self.local_id,
+            resp,
+            src
+        );
+        match resp {
+            Response::Prepare {
+                instance,
+                seq,
+                accepted,
+            } => {
+                let (members, quorums) = match self.voters(instance, src) {
+                    Some(voters) => voters,
+                    None => return,
+                };
+                let proposal = match self.proposals.get_mut(&instance) {
+                    Some(proposal) if proposal.seq == seq => proposal,
+                    _ => {
+                        trace!(
+                            self,
+                            "Server#{} ignore stale prepare resp for instance {}",
+                            self.local_id,
+                            instance
+                        );
+                        return;
+                    }
+                };
+                if quorums.is_phase1_quorum(&members, &proposal.prepared) {
+                    return;
+                }
+                proposal.prepared.insert(src);
+                if let Some(AcceptedProposal { seq, val }) = accepted {
+                    if proposal.highest_seq.is_none_or(|highest| seq > highest) {
+                        proposal.highest_seq = Some(seq);
+                        proposal.value = Some(val);
+                    }
+                }
+                if quorums.is_phase1_quorum(&members, &proposal.prepared) {
+                    let wanted_value = &proposal.wanted_value;
+                    let req = Request::Accept {
+                        instance,
+                        seq: proposal.seq,
+                        value: proposal
+                            .value
+                            .get_or_insert_with(|| wanted_value.clone())
+                            .clone(),
+                    };
+                    // Those who promised may be too few to accept it, depending on the quorums.
+                    // The others refuse it if they promised a higher number since.
+                    let dst = if quorums.is_phase2_quorum(&members, &proposal.prepared) {
+                        &proposal.prepared
+                    } else {
+                        &members
+                    };
+                    let dst = dst.iter().copied().collect();
+                    self.send(dst, Datagram::Request(req));
+                }
+            }
+            Response::Accept { instance, seq } => {
+                // log!("handle accept resp seq: {}", seq);
+                let (members, quorums) = match self.voters(instance, src) {
+                    Some(voters) => voters,
+                    None => return,
+                };
+                let proposal = match self.proposals.get_mut(&instance) {
+                    Some(proposal) if proposal.seq == seq => proposal,
+                    _ => return,
+                };
+                if quorums.is_phase2_quorum(&members, &proposal.accepted) {
+                    return;
+                }
+                proposal.accepted.insert(src);
+                if quorums.is_phase2_quorum(&members, &proposal.accepted) {
+                    let value = proposal.value.clone().unwrap();
+                    trace!(self, "value accepted by quorum: {:?}", value);
+                    // Record it right away so a retry in `settle` skips this slot.
+                    let chosen = value.clone();
+                    self.persist(|storage| storage.set_chosen(instance, chosen));
+                    self.broadcast(Request::Learn {
+                        instance,
+                        value: value.clone(),
+                    });
+                    self.settle(instance, &value, now);
+                    self.apply_chosen(now);
+                }
+            }
+            Response::PrepareRejected {
+                instance,
+                seq,
+                promised,
+            }
+            | Response::AcceptRejected {
+                instance,
+                seq,
+                promised,
+            } => {
+                self.observe_seq(promised);
+                if self.proposals.get(&instance).is_some_and(|p| p.seq == seq) {
+                    trace!(
+                        self,
+                        "Server#{} preempted by {:?} at instance {}",
+                        self.local_id,
+                        promised,
+                        instance
+                    );
+                    // Someone is running for leader over us. Give them a chance to
+                    // win before we try again.
+                    let deposed = self.leadership.ballot() == Some(seq);
+                    if deposed {
+                        trace!(self, "Server #{} steps down as leader.", self.local_id);
+                        let deadline = self.election_deadline(now);
+                        self.leadership.step_down(deadline);
+                    }
+                    self.abandon_round(instance, now, deposed);
+                }
+            }
+            Response::Elect { seq, accepted } => {
+                let voters = match self.leadership.from() {
+                    Some(from) => self.voters(from, src),
+                    None => None,
+                };
+                let (members, quorums) = match voters {
+                    Some(voters) => voters,
+                    None => return,
+                };
+                let is_quorum = |promised: &_| quorums.is_phase1_quorum(&members, promised);
+                if let Some(candidacy) = self
+                    .leadership
+                    .on_promise(src, seq, accepted, is_quorum, now)
+                {
+                    self.take_over(candidacy, now);
+                }
+            }
+            Response::ElectRejected { seq, promised } => {
+                self.observe_seq(promised);
+                self.leadership.on_rejected(seq);
+            }
+            Response::Heartbeat { seq, beat } => {
+                let current = self.first_unchosen_instance();
+                let (members, quorums) = match self.voters(current, src) {
+                    Some(voters) => voters,
+                    None => return,
+                };
+                let acks = match self.leadership.on_heartbeat_ack(src, seq, beat) {
+                    Some(acks) => acks.clone(),
+                    None => return,
+                };
+                // Any value chosen by someone else was accepted by a phase-2 quorum, and
+                // any other leader must be promised by a phase-1 quorum.
+                if quorums.is_phase1_quorum(&members, &acks) {
+                    self.leadership.confirm();
+                }
+                if quorums.is_phase2_quorum(&members, &acks) {
+                    let drift = self.config.clock_drift;
+                    let lease = self
+                        .config
+                        .lease_duration
+                        .mul_f64((1.0 - drift) / (1.0 + drift));
+                    self.leadership.extend_lease(lease);
+                }
+            }
+            // Answers go to clients, which match them to their calls by id.
+            Response::Query { .. }
+            | Response::QueryFailed { .. }
+            | Response::Propose { .. }
+            | Response::Reconfigure { .. }
+            | Response::Leader { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::{FileStorage, MemStorage};
+
+    // #[test]
+    // fn test_encode() {
+    //     let dgram = Datagram::Request(Request::Propose{value: 42});
+    //     let encoded = dgram.encode();
+    //     let decoded: Datagram = bincode::deserialize(&encoded[8..]).unwrap();
+    //     if let Datagram::Request(Request::Propose{ value: 42 }) = decoded {
+    //         //..
+    //     }
+    //     else{
     //         panic!();
     //     }
     // }
+
+    /// Keeps every applied value, and answers with the sum of them so far.
+    #[derive(Debug, Default)]
+    struct Sum(Vec<u32>);
+
+    impl StateMachine<u32> for Sum {
+        type Output = u32;
+        type Snapshot = Vec<u32>;
+
+        fn apply(&mut self, cmd: u32) -> u32 {
+            self.0.push(cmd);
+            self.0.iter().sum()
+        }
+
+        fn snapshot(&self) -> Vec<u32> {
+            self.0.clone()
+        }
+
+        fn restore(&mut self, snapshot: Vec<u32>) {
+            self.0 = snapshot;
+        }
+    }
+
+    /// Server #1 of #1, #2 and #3, driven through its public interface. What it
+    /// produces is collected until the test drains it.
+    struct Node<S: Storage<Entry<u32>>> {
+        paxos: Paxos<u32, S, Sum>,
+        outgoing: Vec<Outgoing<u32, u32>>,
+        events: Vec<Event<u32, u32>>,
+    }
+
+    impl<S: Storage<Entry<u32>>> Node<S> {
+        fn request(&mut self, src: usize, req: Request<u32>, now: Instant) {
+            let dgram = Datagram::Request(req);
+            let effects = self.paxos.handle_incoming(Incoming { src, dgram }, now);
+            self.collect(effects);
+        }
+
+        fn response(&mut self, src: usize, resp: Response<u32, u32>, now: Instant) {
+            let dgram = Datagram::Response(resp);
+            let effects = self.paxos.handle_incoming(Incoming { src, dgram }, now);
+            self.collect(effects);
+        }
+
+        fn tick(&mut self, now: Instant) {
+            let effects = self.paxos.tick(now);
+            self.collect(effects);
+        }
+
+        fn collect(&mut self, effects: Effects<u32, u32>) {
+            self.outgoing.extend(effects.outgoing);
+            self.events.extend(effects.events);
+        }
+
+        fn drain(&mut self) -> Vec<Outgoing<u32, u32>> {
+            std::mem::take(&mut self.outgoing)
+        }
+    }
+
+    /// Client #0's call to have `value` chosen.
+    fn propose(value: u32) -> Request<u32> {
+        let id = RequestId {
+            client: 0,
+            seq: value as u64,
+        };
+        Request::Propose { id, value }
+    }
+
+    /// What `propose(value)` puts in the log.
+    fn entry(value: u32) -> Entry<u32> {
+        let id = RequestId {
+            client: 0,
+            seq: value as u64,
+        };
+        Entry::Value { id, value }
+    }
+
+    fn new_node<S: Storage<Entry<u32>>>(storage: S) -> Node<S> {
+        with_config(storage, PaxosConfig::default())
+    }
+
+    fn with_config<S: Storage<Entry<u32>>>(storage: S, config: PaxosConfig) -> Node<S> {
+        let peers_id = (1..4).collect();
+        let now = Instant::now();
+        let paxos = Paxos::with_config(1, peers_id, storage, Sum::default(), config, now);
+        Node {
+            paxos,
+            outgoing: vec![],
+            events: vec![],
+        }
+    }
+
+    #[test]
+    fn test_instances_are_independent() {
+        let mut node = new_node(MemStorage::new());
+
+        let high = SequenceNumber::new(2, 5);
+        let low = SequenceNumber::new(3, 1);
+        node.request(
+            2,
+            Request::Prepare {
+                instance: 0,
+                seq: high,
+            },
+            Instant::now(),
+        );
+        node.request(
+            3,
+            Request::Prepare {
+                instance: 1,
+                seq: low,
+            },
+            Instant::now(),
+        );
+
+        let mut promised = vec![];
+        for Outgoing { dgram, .. } in node.drain() {
+            if let Datagram::Response(Response::Prepare { instance, seq, .. }) = dgram {
+                promised.push((instance, seq));
+            }
+        }
+        assert_eq!(promised, vec![(0, high), (1, low)]);
+    }
+
+    #[test]
+    fn test_accept_raises_promise() {
+        let mut node = new_node(MemStorage::new());
+        let now = Instant::now();
+        let low = SequenceNumber::new(2, 3);
+        let high = SequenceNumber::new(3, 5);
+        let accept = |seq, value| Request::Accept {
+            instance: 0,
+            seq,
+            value: entry(value),
+        };
+        node.request(
+            2,
+            Request::Prepare {
+                instance: 0,
+                seq: low,
+            },
+            now,
+        );
+        // #3 skipped phase 1 here, as a leader does.
+        node.request(3, accept(high, 5), now);
+        node.drain();
+        // The delayed accept of the promised round must not replace it.
+        node.request(2, accept(low, 7), now);
+        let accepted = node.paxos.storage.accepted(0).unwrap();
+        assert_eq!((accepted.seq(), accepted.val), (high, entry(5)));
+        assert_eq!(node.paxos.storage.promised(0), Some(high));
+        match node.drain().remove(0).dgram {
+            Datagram::Response(Response::AcceptRejected { promised, .. }) => {
+                assert_eq!(promised, high)
+            }
+            dgram => panic!("unexpected dgram {:?}", dgram),
+        }
+    }
+
+    #[test]
+    fn test_next_free_instance_skips_chosen() {
+        let mut node = new_node(MemStorage::new());
+
+        node.request(
+            2,
+            Request::Learn {
+                instance: 0,
+                value: entry(7),
+            },
+            Instant::now(),
+        );
+        node.request(
+            2,
+            Request::Learn {
+                instance: 2,
+                value: entry(9),
+            },
+            Instant::now(),
+        );
+        assert_eq!(node.paxos.next_free_instance(), 1);
+        node.request(0, propose(42), Instant::now());
+        assert_eq!(node.paxos.next_free_instance(), 3);
+    }
+
+    #[test]
+    fn test_recover_from_wal() {
+        let path = std::env::temp_dir().join(format!("paxos-test-{}.wal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let promised = SequenceNumber::new(2, 5);
+        {
+            let mut node = new_node(FileStorage::open(&path).unwrap());
+            node.request(
+                2,
+                Request::Prepare {
+                    instance: 0,
+                    seq: promised,
+                },
+                Instant::now(),
+            );
+            node.request(0, propose(42), Instant::now());
+        }
+
+        let mut node = new_node(FileStorage::open(&path).unwrap());
+        assert_eq!(node.paxos.storage.promised(0), Some(promised));
+        assert_eq!(node.paxos.current_seq, SequenceNumber::new(1, 1));
+        // A lower prepare must still be refused after the restart.
+        node.request(
+            3,
+            Request::Prepare {
+                instance: 0,
+                seq: SequenceNumber::new(3, 4),
+            },
+            Instant::now(),
+        );
+        match node.drain().remove(0).dgram {
+            Datagram::Response(Response::PrepareRejected { promised: p, .. }) => {
+                assert_eq!(p, promised)
+            }
+            dgram => panic!("unexpected dgram {:?}", dgram),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_retry_until_give_up() {
+        let config = PaxosConfig {
+            max_attempts: 2,
+            election_timeout: Duration::from_secs(3600),
+            ..PaxosConfig::default()
+        };
+        let timeout = config.proposal_timeout;
+        let backoff_max = config.backoff_max;
+        let mut node = with_config(MemStorage::new(), config);
+
+        let mut now = Instant::now();
+        node.request(0, propose(42), now);
+        // Nobody answers: time out, back off, then run a second round.
+        now += timeout;
+        node.tick(now);
+        now += backoff_max;
+        node.tick(now);
+        now += timeout;
+        node.tick(now);
+
+        let mut prepares = vec![];
+        let mut result = None;
+        for Outgoing { dst, dgram } in node.drain() {
+            match dgram {
+                Datagram::Request(Request::Prepare { seq, .. }) => prepares.push(seq),
+                Datagram::Response(Response::Propose {
+                    value, result: r, ..
+                }) => {
+                    assert_eq!(dst, (0..1).collect());
+                    assert_eq!(value, 42);
+                    result = Some(r);
+                }
+                _ => panic!("unexpected dgram {:?}", dgram),
+            }
+        }
+        assert_eq!(
+            prepares,
+            vec![SequenceNumber::new(1, 1), SequenceNumber::new(1, 2)]
+        );
+        assert_eq!(result, Some(ProposeResult::Unknown { instance: 0 }));
+        assert!(node.paxos.proposals.is_empty());
+    }
+
+    #[test]
+    fn test_preempted_round_retries_above_promise() {
+        let mut node = new_node(MemStorage::new());
+        let now = Instant::now();
+        node.request(0, propose(42), now);
+        let promised = SequenceNumber::new(3, 7);
+        let nack = Response::PrepareRejected {
+            instance: 0,
+            seq: SequenceNumber::new(1, 1),
+            promised,
+        };
+        node.response(2, nack.clone(), now);
+        // A late duplicate for the abandoned round changes nothing.
+        node.response(3, nack, now);
+
+        let mut prepares = vec![];
+        for Outgoing { dgram, .. } in node.drain() {
+            if let Datagram::Request(Request::Prepare { seq, .. }) = dgram {
+                prepares.push(seq);
+            }
+        }
+        assert_eq!(
+            prepares,
+            vec![SequenceNumber::new(1, 1), SequenceNumber::new(1, 8)]
+        );
+        assert!(prepares[1] > promised);
+        assert_eq!(node.paxos.proposals[&0].attempts, 2);
+    }
+
+    #[test]
+    fn test_elected_leader_skips_phase_1() {
+        let config = PaxosConfig::default();
+        let now = Instant::now() + 2 * config.election_timeout;
+        let mut node = with_config(MemStorage::new(), config);
+
+        node.tick(now);
+        let seq = SequenceNumber::new(1, 1);
+        let accepted = AcceptedProposal::new(SequenceNumber::new(3, 1), entry(5));
+        let promise = Response::Elect {
+            seq,
+            accepted: vec![(1, accepted)],
+        };
+        node.response(2, promise, now);
+        let promise = Response::Elect {
+            seq,
+            accepted: vec![],
+        };
+        node.response(3, promise, now);
+        assert_eq!(node.paxos.leadership.ballot(), Some(seq));
+        node.request(0, propose(42), now);
+
+        let mut accepts = vec![];
+        for Outgoing { dgram, .. } in node.drain() {
+            match dgram {
+                Datagram::Request(Request::Accept {
+                    instance,
+                    seq: s,
+                    value,
+                }) => {
+                    assert_eq!(s, seq);
+                    accepts.push((instance, value));
+                }
+                Datagram::Request(Request::Elect { from: 0, seq: s }) => assert_eq!(s, seq),
+                _ => panic!("unexpected dgram {:?}", dgram),
+            }
+        }
+        accepts.sort_by_key(|&(instance, _)| instance);
+        // The hole below the recovered value is filled with a no-op.
+        assert_eq!(
+            accepts,
+            vec![(0, Entry::Noop), (1, entry(5)), (2, entry(42))]
+        );
+    }
+
+    #[test]
+    fn test_leader_keeps_abandoned_instance() {
+        let config = PaxosConfig {
+            max_attempts: 1,
+            ..PaxosConfig::default()
+        };
+        let timeout = config.proposal_timeout;
+        let now = Instant::now() + 2 * config.election_timeout;
+        let mut node = with_config(MemStorage::new(), config);
+        node.tick(now);
+        let seq = SequenceNumber::new(1, 1);
+        for src in 2..4 {
+            let promise = Response::Elect {
+                seq,
+                accepted: vec![],
+            };
+            node.response(src, promise, now);
+        }
+        node.request(0, propose(42), now);
+        node.tick(now + timeout);
+        assert!(node.drain().iter().any(|out| matches!(
+            out.dgram,
+            Datagram::Response(Response::Propose {
+                result: ProposeResult::Unknown { instance: 0 },
+                ..
+            })
+        )));
+
+        // Acceptors may hold 42 under our ballot in instance 0, so 43 goes elsewhere.
+        node.request(0, propose(43), now + timeout);
+        let mut accepts = vec![];
+        for Outgoing { dgram, .. } in node.drain() {
+            if let Datagram::Request(Request::Accept {
+                instance, value, ..
+            }) = dgram
+            {
+                accepts.push((instance, value));
+            }
+        }
+        assert_eq!(accepts, vec![(1, entry(43))]);
+        assert_eq!(node.paxos.proposals[&0].wanted_value, entry(42));
+    }
+
+    #[test]
+    fn test_linearizable_reads() {
+        let config = PaxosConfig::default();
+        let lease = config.lease_duration;
+        let now = Instant::now() + 2 * config.election_timeout;
+        let mut node = with_config(MemStorage::new(), config);
+        node.tick(now);
+        let seq = SequenceNumber::new(1, 1);
+        for src in 2..4 {
+            let promise = Response::Elect {
+                seq,
+                accepted: vec![],
+            };
+            node.response(src, promise, now);
+        }
+        let learn = Request::Learn {
+            instance: 0,
+            value: entry(7),
+        };
+        node.request(2, learn, now);
+        let query = |consistency| Request::Query {
+            id: RequestId { client: 0, seq: 0 },
+            instance: 0,
+            consistency,
+        };
+        let answers = |node: &mut Node<_>| {
+            let mut answers = vec![];
+            for Outgoing { dgram, .. } in node.drain() {
+                match dgram {
+                    Datagram::Response(Response::Query { val, .. }) => answers.push(val),
+                    Datagram::Response(Response::QueryFailed { .. }) => answers.push(None),
+                    _ => {}
+                }
+            }
+            answers
+        };
+        assert_eq!(answers(&mut node), vec![]);
+
+        // Both wait for the acceptors to acknowledge a heartbeat sent after they came.
+        node.request(0, query(ReadConsistency::Quorum), now);
+        node.request(0, query(ReadConsistency::Lease), now);
+        node.tick(now);
+        node.response(2, Response::Heartbeat { seq, beat: 1 }, now);
+        assert_eq!(answers(&mut node), vec![]);
+        node.response(3, Response::Heartbeat { seq, beat: 1 }, now);
+        let chosen = Some(entry(7));
+        assert_eq!(answers(&mut node), vec![chosen.clone(), chosen.clone()]);
+
+        // Which grants a lease.
+        let later = now + lease / 2;
+        node.request(0, query(ReadConsistency::Lease), later);
+        node.request(0, query(ReadConsistency::Quorum), later);
+        assert_eq!(answers(&mut node), vec![chosen.clone()]);
+        let expired = now + lease;
+        node.request(0, query(ReadConsistency::Lease), expired);
+        assert_eq!(answers(&mut node), vec![]);
+
+        // Unconfirmed reads eventually fail.
+        node.tick(expired + Duration::from_secs(1));
+        assert_eq!(answers(&mut node), vec![None, None]);
+    }
+
+    #[test]
+    fn test_acceptor_keeps_lease() {
+        let config = PaxosConfig::default();
+        let lease = config.lease_duration;
+        let mut node = with_config(MemStorage::new(), config);
+        let now = Instant::now();
+        let heartbeat = Request::Heartbeat {
+            seq: SequenceNumber::new(3, 1),
+            chosen_upto: 0,
+            beat: 1,
+        };
+        node.request(3, heartbeat, now);
+        node.drain();
+        let elect = Request::Elect {
+            from: 0,
+            seq: SequenceNumber::new(2, 2),
+        };
+        node.request(2, elect.clone(), now + lease / 2);
+        assert!(matches!(
+            node.drain()[..],
+            [Outgoing {
+                dgram: Datagram::Response(Response::ElectRejected { promised, .. }),
+                ..
+            }] if promised == SequenceNumber::new(3, 1)
+        ));
+        node.request(2, elect, now + lease);
+        assert!(matches!(
+            node.drain()[..],
+            [Outgoing {
+                dgram: Datagram::Response(Response::Elect { .. }),
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn test_restarted_acceptor_keeps_lease() {
+        let config = PaxosConfig::default();
+        let lease = config.lease_duration;
+        let mut node = with_config(MemStorage::new(), config.clone());
+        let heartbeat = Request::Heartbeat {
+            seq: SequenceNumber::new(3, 1),
+            chosen_upto: 0,
+            beat: 1,
+        };
+        node.request(3, heartbeat, Instant::now());
+        node.drain();
+
+        // However long it was down, the lease may have started just before the crash.
+        let mut node = with_config(node.paxos.storage().clone(), config);
+        let now = Instant::now();
+        let elect = Request::Elect {
+            from: 0,
+            seq: SequenceNumber::new(2, 2),
+        };
+        node.request(2, elect.clone(), now + lease / 2);
+        assert!(matches!(
+            node.drain()[..],
+            [Outgoing {
+                dgram: Datagram::Response(Response::ElectRejected { .. }),
+                ..
+            }]
+        ));
+        node.request(2, elect, now + lease);
+        assert!(matches!(
+            node.drain()[..],
+            [Outgoing {
+                dgram: Datagram::Response(Response::Elect { .. }),
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn test_no_heartbeat_ack_after_higher_accept() {
+        let mut node = new_node(MemStorage::new());
+        let now = Instant::now();
+        let old = SequenceNumber::new(2, 1);
+        let heartbeat = |beat| Request::Heartbeat {
+            seq: old,
+            chosen_upto: 0,
+            beat,
+        };
+        node.request(2, heartbeat(1), now);
+        assert!(matches!(
+            node.drain()[0].dgram,
+            Datagram::Response(Response::Heartbeat { beat: 1, .. })
+        ));
+
+        // A newer leader we never promised anything to got its proposal accepted.
+        let accept = Request::Accept {
+            instance: 0,
+            seq: SequenceNumber::new(3, 2),
+            value: entry(5),
+        };
+        node.request(3, accept, now);
+        node.drain();
+        node.request(2, heartbeat(2), now);
+        assert!(node.drain().is_empty());
+    }
+
+    #[test]
+    fn test_follower_defers_to_leader() {
+        let mut node = new_node(MemStorage::new());
+        let now = Instant::now();
+        let leader_seq = SequenceNumber::new(3, 4);
+        node.request(
+            3,
+            Request::Elect {
+                from: 0,
+                seq: leader_seq,
+            },
+            now,
+        );
+        node.request(
+            3,
+            Request::Heartbeat {
+                seq: leader_seq,
+                chosen_upto: 0,
+                beat: 1,
+            },
+            now,
+        );
+        // The promise to the leader covers instances nobody has touched yet.
+        node.request(
+            2,
+            Request::Prepare {
+                instance: 5,
+                seq: SequenceNumber::new(2, 2),
+            },
+            now,
+        );
+        node.request(0, propose(42), now);
+
+        let mut dgrams = vec![];
+        for Outgoing { dst, dgram } in node.drain() {
+            dgrams.push((dst, dgram));
+        }
+        assert!(matches!(
+            dgrams[0].1,
+            Datagram::Response(Response::Elect { seq, .. }) if seq == leader_seq
+        ));
+        assert!(matches!(
+            dgrams[1].1,
+            Datagram::Response(Response::Heartbeat { seq, beat: 1 }) if seq == leader_seq
+        ));
+        assert!(matches!(
+            dgrams[2].1,
+            Datagram::Response(Response::PrepareRejected { instance: 5, promised, .. })
+                if promised == leader_seq
+        ));
+        assert_eq!(dgrams[3].0, (3..4).collect());
+        assert!(matches!(
+            dgrams[3].1,
+            Datagram::Request(Request::Forward {
+                id: RequestId { client: 0, seq: 42 },
+                ref value,
+            }) if *value == entry(42)
+        ));
+        assert!(node.paxos.proposals.is_empty());
+    }
+
+    #[test]
+    fn test_answer_goes_to_caller() {
+        let mut node = new_node(MemStorage::new());
+        let id = RequestId { client: 5, seq: 9 };
+        // Passed on by #2, but #5 made the call. We aren't leading, so we can't answer.
+        let query = Request::ForwardQuery {
+            id,
+            instance: 0,
+            consistency: ReadConsistency::Quorum,
+        };
+        node.request(2, query, Instant::now());
+        let outgoing = node.drain();
+        assert_eq!(outgoing.len(), 1);
+        assert_eq!(outgoing[0].dst, (5..6).collect());
+        assert!(matches!(
+            outgoing[0].dgram,
+            Datagram::Response(Response::QueryFailed { id: i, instance: 0 }) if i == id
+        ));
+    }
+
+    #[test]
+    fn test_reconfiguration_takes_effect_after_window() {
+        let config = PaxosConfig {
+            reconfig_window: 2,
+            election_timeout: Duration::from_secs(3600),
+            ..PaxosConfig::default()
+        };
+        let mut node = with_config(MemStorage::new(), config);
+        let now = Instant::now();
+        let members: BTreeSet<usize> = [1, 4, 5].into();
+        node.request(
+            2,
+            Request::Learn {
+                instance: 0,
+                value: Entry::Reconfigure(members.clone()),
+            },
+            now,
+        );
+        for value in 42..45 {
+            node.request(0, propose(value), now);
+        }
+
+        let mut prepares = vec![];
+        for Outgoing { dst, dgram } in node.drain() {
+            if let Datagram::Request(Request::Prepare { instance, .. }) = dgram {
+                let mut dst: Vec<usize> = dst.into_iter().collect();
+                dst.sort_unstable();
+                prepares.push((instance, dst));
+            }
+        }
+        // Instance 3 waits until instance 1 is chosen to know its acceptors.
+        assert_eq!(prepares, vec![(1, vec![1, 2, 3]), (2, vec![1, 4, 5])]);
+        let reconfigured = Event::Reconfigured {
+            instance: 2,
+            members,
+        };
+        assert_eq!(node.events, vec![reconfigured]);
+
+        // Only the new members count towards a majority in instance 2.
+        let promise = |seq| Response::Prepare {
+            instance: 2,
+            seq,
+            accepted: None,
+        };
+        let seq = node.paxos.proposals[&2].seq;
+        node.response(2, promise(seq), now);
+        node.response(3, promise(seq), now);
+        assert!(node.drain().is_empty());
+        node.response(4, promise(seq), now);
+        node.response(5, promise(seq), now);
+        assert!(matches!(
+            node.drain()[..],
+            [Outgoing {
+                dgram: Datagram::Request(Request::Accept { instance: 2, .. }),
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn test_lagging_server_installs_snapshot() {
+        let config = PaxosConfig {
+            snapshot_interval: Some(2),
+            snapshot_chunk_size: 16,
+            ..PaxosConfig::default()
+        };
+        let mut node = with_config(MemStorage::new(), config.clone());
+        let now = Instant::now();
+        for (instance, value) in vec![3, 4, 5].into_iter().enumerate() {
+            let value = entry(value);
+            node.request(2, Request::Learn { instance, value }, now);
+        }
+        assert_eq!(node.paxos.storage().snapshot().unwrap().upto, 2);
+        assert_eq!(node.paxos.storage().chosen(1), None);
+        node.drain();
+
+        // Whoever asks about the compacted instances gets the snapshot instead, in
+        // chunks, but not again and again while it's on its way.
+        node.request(3, Request::CatchUp { from: 0 }, now);
+        let seq = SequenceNumber::new(3, 1);
+        node.request(3, Request::Prepare { instance: 1, seq }, now);
+        node.request(3, Request::Elect { from: 0, seq }, now);
+        let dgrams: Vec<_> = node.drain().into_iter().map(|o| o.dgram).collect();
+        let (chunks, rest): (Vec<_>, Vec<_>) = dgrams
+            .into_iter()
+            .partition(|dgram| matches!(dgram, Datagram::Request(Request::InstallSnapshot { .. })));
+        assert!(chunks.len() > 1);
+        assert!(matches!(
+            rest[..],
+            [Datagram::Request(Request::Learn { instance: 2, .. })]
+        ));
+        let later = now + config.snapshot_resend_interval;
+        node.request(3, Request::Prepare { instance: 1, seq }, later);
+        let resent: Vec<_> = node.drain().into_iter().map(|o| o.dgram).collect();
+        assert_eq!(resent.len(), chunks.len());
+
+        // A chunk lost on the way leaves a gap that only the next sending fills.
+        let mut fresh = with_config(MemStorage::new(), config);
+        let lost = chunks.into_iter().enumerate().filter(|&(i, _)| i != 1);
+        for dgram in lost.map(|(_, dgram)| dgram).chain(resent) {
+            if let Datagram::Request(req) = dgram {
+                assert!(fresh.events.is_empty());
+                fresh.request(1, req, now);
+            }
+        }
+        assert_eq!(fresh.paxos.state_machine.0, vec![3, 4]);
+        assert_eq!(fresh.events, vec![Event::SnapshotInstalled { upto: 2 }]);
+        fresh.request(
+            1,
+            Request::Learn {
+                instance: 2,
+                value: entry(5),
+            },
+            now,
+        );
+        assert_eq!(
+            fresh.events[1],
+            Event::Applied {
+                instance: 2,
+                value: 5,
+                output: 12,
+            }
+        );
+    }
+
+    #[test]
+    fn test_apply_in_slot_order() {
+        let mut node = new_node(MemStorage::new());
+        let now = Instant::now();
+        // 5 is another client's, or it would look like a leftover of the call for 42.
+        let other = Entry::Value {
+            id: RequestId { client: 7, seq: 0 },
+            value: 5,
+        };
+        let learn = |instance, value| Request::Learn { instance, value };
+        node.request(2, learn(1, other.clone()), now);
+        assert!(node.paxos.state_machine.0.is_empty());
+        node.request(0, propose(42), now);
+        node.request(2, learn(0, entry(42)), now);
+        assert_eq!(node.paxos.state_machine.0, vec![42, 5]);
+
+        // A follower that fell behind asks the leader to fill it in.
+        let seq = SequenceNumber::new(3, 1);
+        let heartbeat = Request::Heartbeat {
+            seq,
+            chosen_upto: 4,
+            beat: 1,
+        };
+        node.request(3, heartbeat, now);
+        node.request(2, Request::CatchUp { from: 1 }, now);
+
+        let mut result = None;
+        let mut catch_up = None;
+        let mut learns = vec![];
+        for Outgoing { dst, dgram } in node.drain() {
+            match dgram {
+                Datagram::Response(Response::Propose {
+                    value: 42,
+                    result: r,
+                    ..
+                }) => {
+                    assert_eq!(dst, (0..1).collect());
+                    result = Some(r);
+                }
+                Datagram::Request(Request::CatchUp { from }) => {
+                    assert_eq!(dst, (3..4).collect());
+                    catch_up = Some(from);
+                }
+                Datagram::Request(Request::Learn { instance, value }) => {
+                    assert_eq!(dst, (2..3).collect());
+                    learns.push((instance, value));
+                }
+                Datagram::Request(Request::Prepare { .. })
+                | Datagram::Response(Response::Heartbeat { .. }) => {}
+                _ => panic!("unexpected dgram {:?}", dgram),
+            }
+        }
+        let output = 42;
+        assert_eq!(
+            result,
+            Some(ProposeResult::Chosen {
+                instance: 0,
+                output
+            })
+        );
+        assert_eq!(catch_up, Some(2));
+        assert_eq!(learns, vec![(1, other)]);
+        let applied = |instance, value, output| Event::Applied {
+            instance,
+            value,
+            output,
+        };
+        assert_eq!(
+            node.events,
+            vec![
+                applied(0, 42, 42),
+                applied(1, 5, 47),
+                Event::LeaderChanged { leader: Some(3) }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retried_command_takes_effect_once() {
+        let config = PaxosConfig {
+            session_timeout: 3,
+            ..PaxosConfig::default()
+        };
+        let mut node = with_config(MemStorage::new(), config);
+        let now = Instant::now();
+        let learn = |instance, value| Request::Learn { instance, value };
+        // The call for 42 got chosen twice after a retry.
+        node.request(2, learn(0, entry(42)), now);
+        node.request(2, learn(1, entry(42)), now);
+        assert_eq!(node.paxos.state_machine.0, vec![42]);
+
+        // Retried once more, it's answered like the first time.
+        node.drain();
+        node.request(0, propose(42), now);
+        let outgoing = node.drain();
+        assert_eq!(outgoing.len(), 1);
+        assert!(matches!(
+            outgoing[0].dgram,
+            Datagram::Response(Response::Propose {
+                result: ProposeResult::Chosen {
+                    instance: 0,
+                    output: 42
+                },
+                ..
+            })
+        ));
+        assert!(node.paxos.proposals.is_empty());
+
+        // Once the session expired, it takes effect again.
+        let other = |seq| Entry::Value {
+            id: RequestId { client: 7, seq },
+            value: 1,
+        };
+        node.request(2, learn(2, other(0)), now);
+        node.request(2, learn(3, other(1)), now);
+        node.request(2, learn(4, entry(42)), now);
+        assert_eq!(node.paxos.state_machine.0, vec![42, 1, 1, 42]);
+    }
+
+    #[test]
+    fn test_batch_proposals() {
+        let config = PaxosConfig {
+            batch_window: Duration::from_millis(10),
+            ..PaxosConfig::default()
+        };
+        let mut node = with_config(MemStorage::new(), config);
+        let now = Instant::now();
+        let call = |client| RequestId { client, seq: 0 };
+        node.request(
+            4,
+            Request::Propose {
+                id: call(4),
+                value: 1,
+            },
+            now,
+        );
+        node.request(
+            5,
+            Request::Propose {
+                id: call(5),
+                value: 2,
+            },
+            now,
+        );
+        assert!(node.drain().is_empty());
+
+        // Both go in the same instance once the window is over.
+        node.tick(now + Duration::from_millis(10));
+        let batch = Entry::Batch(vec![(call(4), 1), (call(5), 2)]);
+        assert_eq!(node.paxos.proposals[&0].wanted_value, batch);
+
+        // And each caller hears of its own command.
+        node.drain();
+        node.request(
+            2,
+            Request::Learn {
+                instance: 0,
+                value: batch,
+            },
+            now,
+        );
+        assert_eq!(node.paxos.state_machine.0, vec![1, 2]);
+        let mut outputs = vec![];
+        for Outgoing { dst, dgram } in node.drain() {
+            if let Datagram::Response(Response::Propose { id, result, .. }) = dgram {
+                assert_eq!(dst, (id.client..id.client + 1).collect());
+                outputs.push((id.client, result));
+            }
+        }
+        let chosen = |output| ProposeResult::Chosen {
+            instance: 0,
+            output,
+        };
+        assert_eq!(outputs, vec![(4, chosen(1)), (5, chosen(3))]);
+    }
 }
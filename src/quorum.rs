@@ -0,0 +1,280 @@
+//! Which sets of acceptors may complete each phase of a round.
+//!
+//! Paxos only needs every phase-1 quorum to intersect every phase-2 quorum of the
+//! same acceptors (Flexible Paxos). Majorities are the usual choice, but shrinking
+//! phase-2 quorums makes `Accept`s, which run on every proposal, faster and less
+//! demanding, at the cost of larger phase-1 quorums, which only elections and
+//! leaderless rounds need.
+
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::fmt::{self, Debug};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Decides whether a set of acceptors is a quorum.
+///
+/// `voters` are always among `members`, the acceptors of the instance at hand.
+/// Implementations must make every phase-1 quorum of `members` intersect every
+/// phase-2 quorum of them, or different values may be chosen.
+pub trait QuorumSystem: Debug + Send + Sync {
+    fn is_phase1_quorum(&self, members: &BTreeSet<usize>, voters: &BTreeSet<usize>) -> bool;
+    fn is_phase2_quorum(&self, members: &BTreeSet<usize>, voters: &BTreeSet<usize>) -> bool;
+}
+
+/// More than half of the acceptors, in both phases.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Majority;
+
+impl QuorumSystem for Majority {
+    fn is_phase1_quorum(&self, members: &BTreeSet<usize>, voters: &BTreeSet<usize>) -> bool {
+        voters.len() > members.len() / 2
+    }
+
+    fn is_phase2_quorum(&self, members: &BTreeSet<usize>, voters: &BTreeSet<usize>) -> bool {
+        voters.len() > members.len() / 2
+    }
+}
+
+/// `phase2` acceptors complete phase 2, and enough of them to meet all of those,
+/// `N - phase2 + 1`, phase 1. `phase2` is capped at the number of acceptors `N`.
+#[derive(Debug, Clone, Copy)]
+pub struct Flexible {
+    pub phase2: usize,
+}
+
+impl Flexible {
+    fn phase2(&self, members: &BTreeSet<usize>) -> usize {
+        self.phase2.max(1).min(members.len())
+    }
+}
+
+impl QuorumSystem for Flexible {
+    fn is_phase1_quorum(&self, members: &BTreeSet<usize>, voters: &BTreeSet<usize>) -> bool {
+        voters.len() > members.len() - self.phase2(members)
+    }
+
+    fn is_phase2_quorum(&self, members: &BTreeSet<usize>, voters: &BTreeSet<usize>) -> bool {
+        voters.len() >= self.phase2(members)
+    }
+}
+
+/// More than half of the total weight of the acceptors, in both phases. Acceptors
+/// missing from `weights` weigh 1.
+#[derive(Debug, Clone, Default)]
+pub struct Weighted {
+    pub weights: HashMap<usize, usize>,
+}
+
+impl Weighted {
+    fn weight<'a>(&self, ids: impl IntoIterator<Item = &'a usize>) -> usize {
+        ids.into_iter()
+            .map(|id| self.weights.get(id).copied().unwrap_or(1))
+            .sum()
+    }
+
+    fn is_quorum(&self, members: &BTreeSet<usize>, voters: &BTreeSet<usize>) -> bool {
+        self.weight(voters) * 2 > self.weight(members)
+    }
+}
+
+impl QuorumSystem for Weighted {
+    fn is_phase1_quorum(&self, members: &BTreeSet<usize>, voters: &BTreeSet<usize>) -> bool {
+        self.is_quorum(members, voters)
+    }
+
+    fn is_phase2_quorum(&self, members: &BTreeSet<usize>, voters: &BTreeSet<usize>) -> bool {
+        self.is_quorum(members, voters)
+    }
+}
+
+/// The acceptors, in ascending order, fill a grid row by row, `columns` to a row
+/// (the last one may be shorter). A whole row completes phase 2, and an acceptor
+/// from every row phase 1.
+#[derive(Debug, Clone, Copy)]
+pub struct Grid {
+    pub columns: usize,
+}
+
+impl Grid {
+    fn rows<'a>(&self, members: &'a BTreeSet<usize>) -> impl Iterator<Item = Vec<&'a usize>> {
+        let members: Vec<_> = members.iter().collect();
+        let columns = self.columns.max(1);
+        (0..members.len())
+            .step_by(columns)
+            .map(move |start| members[start..members.len().min(start + columns)].to_vec())
+    }
+}
+
+impl QuorumSystem for Grid {
+    fn is_phase1_quorum(&self, members: &BTreeSet<usize>, voters: &BTreeSet<usize>) -> bool {
+        self.rows(members)
+            .all(|row| row.iter().any(|id| voters.contains(id)))
+    }
+
+    fn is_phase2_quorum(&self, members: &BTreeSet<usize>, voters: &BTreeSet<usize>) -> bool {
+        self.rows(members)
+            .any(|row| row.iter().all(|id| voters.contains(id)))
+    }
+}
+
+/// One of the quorum systems above, described by its settings.
+///
+/// Written `majority`, `flexible=Q2` (the size of phase-2 quorums), `grid=COLUMNS` or
+/// `weights=ID:W,ID:W,..` (servers not listed weigh 1), in the console and in
+/// cluster config files alike.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum QuorumConfig {
+    #[default]
+    Majority,
+    Flexible {
+        phase2: usize,
+    },
+    Weighted {
+        weights: HashMap<usize, usize>,
+    },
+    Grid {
+        columns: usize,
+    },
+}
+
+impl QuorumConfig {
+    pub fn build(&self) -> Arc<dyn QuorumSystem> {
+        match *self {
+            QuorumConfig::Majority => Arc::new(Majority),
+            QuorumConfig::Flexible { phase2 } => Arc::new(Flexible { phase2 }),
+            QuorumConfig::Weighted { ref weights } => Arc::new(Weighted {
+                weights: weights.clone(),
+            }),
+            QuorumConfig::Grid { columns } => Arc::new(Grid { columns }),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseQuorumError(String);
+
+impl fmt::Display for ParseQuorumError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid quorums `{}`", self.0)
+    }
+}
+
+impl FromStr for QuorumConfig {
+    type Err = ParseQuorumError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseQuorumError(s.to_string());
+        let number = |s: &str| s.parse::<usize>().map_err(|_| error());
+        let mut setting = s.splitn(2, '=');
+        match (setting.next().ok_or_else(error)?, setting.next()) {
+            ("majority", None) => Ok(QuorumConfig::Majority),
+            ("flexible", Some(phase2)) => Ok(QuorumConfig::Flexible {
+                phase2: number(phase2)?,
+            }),
+            ("grid", Some(columns)) => Ok(QuorumConfig::Grid {
+                columns: number(columns)?,
+            }),
+            ("weights", Some(weights)) => {
+                let weights = weights
+                    .split(',')
+                    .map(|weight| {
+                        let mut weight = weight.splitn(2, ':');
+                        let id = number(weight.next().ok_or_else(error)?)?;
+                        Ok((id, number(weight.next().ok_or_else(error)?)?))
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(QuorumConfig::Weighted { weights })
+            }
+            _ => Err(error()),
+        }
+    }
+}
+
+impl TryFrom<String> for QuorumConfig {
+    type Error = ParseQuorumError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Every subset of `members`.
+    fn subsets(members: &BTreeSet<usize>) -> Vec<BTreeSet<usize>> {
+        let members: Vec<_> = members.iter().copied().collect();
+        (0..1 << members.len())
+            .map(|mask| {
+                (0..members.len())
+                    .filter(|i| mask & (1 << i) != 0)
+                    .map(|i| members[i])
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Check that phase-1 and phase-2 quorums intersect, and return the size of the
+    /// smallest quorum of each phase.
+    fn check(quorums: &dyn QuorumSystem, members: &BTreeSet<usize>) -> (usize, usize) {
+        let subsets = subsets(members);
+        let phase1: Vec<_> = subsets
+            .iter()
+            .filter(|voters| quorums.is_phase1_quorum(members, voters))
+            .collect();
+        let phase2: Vec<_> = subsets
+            .iter()
+            .filter(|voters| quorums.is_phase2_quorum(members, voters))
+            .collect();
+        for q1 in &phase1 {
+            for q2 in &phase2 {
+                assert!(!q1.is_disjoint(q2), "{:?} and {:?} are disjoint", q1, q2);
+            }
+        }
+        let smallest = |quorums: &[&BTreeSet<usize>]| quorums.iter().map(|q| q.len()).min();
+        (smallest(&phase1).unwrap(), smallest(&phase2).unwrap())
+    }
+
+    #[test]
+    fn test_majority() {
+        assert_eq!(check(&Majority, &(1..=5).collect()), (3, 3));
+        assert_eq!(check(&Majority, &(1..=4).collect()), (3, 3));
+    }
+
+    #[test]
+    fn test_flexible() {
+        let members = (1..=5).collect();
+        assert_eq!(check(&Flexible { phase2: 2 }, &members), (4, 2));
+        assert_eq!(check(&Flexible { phase2: 0 }, &members), (5, 1));
+        assert_eq!(check(&Flexible { phase2: 9 }, &members), (1, 5));
+    }
+
+    #[test]
+    fn test_weighted() {
+        let weighted = Weighted {
+            weights: vec![(1, 3)].into_iter().collect(),
+        };
+        let members = (1..=4).collect();
+        assert_eq!(check(&weighted, &members), (2, 2));
+        assert!(weighted.is_phase2_quorum(&members, &[1, 2].into()));
+        assert!(!weighted.is_phase2_quorum(&members, &[2, 3, 4].into()));
+    }
+
+    #[test]
+    fn test_grid() {
+        let grid = Grid { columns: 3 };
+        let members = (1..=8).collect();
+        // 1 2 3
+        // 4 5 6
+        // 7 8
+        assert_eq!(check(&grid, &members), (3, 2));
+        assert!(grid.is_phase2_quorum(&members, &[4, 5, 6].into()));
+        assert!(!grid.is_phase2_quorum(&members, &[3, 4, 5].into()));
+        assert!(grid.is_phase1_quorum(&members, &[2, 6, 7].into()));
+        assert!(!grid.is_phase1_quorum(&members, &[1, 2, 6].into()));
+    }
+}
@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::paxos::RequestId;
+
+/// The last command of a client that took effect, and what it yielded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Session<O> {
+    pub seq: u64,
+    /// Where the command was chosen.
+    pub instance: usize,
+    pub output: O,
+}
+
+/// What the sessions know of a client's call.
+#[derive(Debug, PartialEq)]
+pub enum Seen<'a, O> {
+    /// It hasn't taken effect yet.
+    New,
+    /// It's the last call of its client that took effect.
+    Last(&'a Session<O>),
+    /// A later call of its client took effect, so it's a leftover nobody waits for.
+    Old,
+}
+
+/// Makes the commands clients have chosen take effect at most once, however often
+/// they're retried: part of the replicated state, updated as the log is applied.
+///
+/// A client makes one call at a time, numbering them in increasing order, so only
+/// the last call of each client needs to be remembered, along with its output for
+/// the retries to be answered with.
+///
+/// A session expires `timeout` instances after the last command of its client took
+/// effect. Counting instances rather than time makes every server forget it at the
+/// same point of the log. A command retried after that takes effect again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sessions<O> {
+    sessions: BTreeMap<usize, Session<O>>,
+    /// The clients by the instance of their session, oldest first.
+    by_instance: BTreeSet<(usize, usize)>,
+}
+
+impl<O> Default for Sessions<O> {
+    fn default() -> Self {
+        Self {
+            sessions: BTreeMap::new(),
+            by_instance: BTreeSet::new(),
+        }
+    }
+}
+
+impl<O> Sessions<O> {
+    pub fn seen(&self, id: RequestId) -> Seen<'_, O> {
+        match self.sessions.get(&id.client) {
+            Some(session) if session.seq == id.seq => Seen::Last(session),
+            Some(session) if session.seq > id.seq => Seen::Old,
+            _ => Seen::New,
+        }
+    }
+
+    /// The call `id`, chosen in `instance`, took effect and yielded `output`.
+    pub fn record(&mut self, id: RequestId, instance: usize, output: O) {
+        let session = Session {
+            seq: id.seq,
+            instance,
+            output,
+        };
+        if let Some(old) = self.sessions.insert(id.client, session) {
+            self.by_instance.remove(&(old.instance, id.client));
+        }
+        self.by_instance.insert((instance, id.client));
+    }
+
+    /// Forget the sessions of the clients that had nothing take effect in the
+    /// `timeout` instances up to `instance`.
+    pub fn expire(&mut self, instance: usize, timeout: usize) {
+        while let Some(&(last, client)) = self.by_instance.iter().next() {
+            if last + timeout > instance {
+                break;
+            }
+            self.by_instance.remove(&(last, client));
+            self.sessions.remove(&client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sessions() {
+        let call = |client, seq| RequestId { client, seq };
+        let mut sessions = Sessions::default();
+        assert_eq!(sessions.seen(call(1, 5)), Seen::New);
+        sessions.record(call(1, 5), 0, "a");
+        sessions.record(call(2, 3), 1, "b");
+        assert_eq!(
+            sessions.seen(call(1, 5)),
+            Seen::Last(&Session {
+                seq: 5,
+                instance: 0,
+                output: "a"
+            })
+        );
+        assert_eq!(sessions.seen(call(1, 4)), Seen::Old);
+        assert_eq!(sessions.seen(call(1, 6)), Seen::New);
+
+        sessions.expire(10, 10);
+        assert_eq!(sessions.seen(call(1, 5)), Seen::New);
+        assert!(matches!(sessions.seen(call(2, 3)), Seen::Last(_)));
+
+        // A later call moves the client's session along.
+        sessions.record(call(2, 4), 12, "c");
+        sessions.expire(11, 10);
+        assert!(matches!(sessions.seen(call(2, 4)), Seen::Last(_)));
+        sessions.expire(22, 10);
+        assert_eq!(sessions.seen(call(2, 4)), Seen::New);
+        assert_eq!(sessions, Sessions::default());
+    }
+}
@@ -0,0 +1,670 @@
+//! A deterministic, in-process cluster for testing the protocol.
+//!
+//! Servers talk over a simulated network driven by a seeded RNG, which decides
+//! how long each datagram takes, and whether it's dropped or duplicated. Time is
+//! simulated too, so a run is fully determined by its seed and `SimConfig`.
+
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::hash::Hasher;
+use std::time::{Duration, Instant};
+
+use crate::paxos::*;
+use crate::state_machine::StateMachine;
+use crate::storage::MemStorage;
+
+/// Clients are numbered from here on, one for each call, since a client makes one
+/// call at a time. Whatever servers send them is recorded as a response.
+const CLIENT: usize = 1000;
+
+/// The values applied so far. Applying one answers with its position.
+#[derive(Debug, Default)]
+pub struct Log(Vec<u32>);
+
+impl StateMachine<u32> for Log {
+    type Output = usize;
+    type Snapshot = Vec<u32>;
+
+    fn apply(&mut self, cmd: u32) -> usize {
+        self.0.push(cmd);
+        self.0.len() - 1
+    }
+
+    fn snapshot(&self) -> Vec<u32> {
+        self.0.clone()
+    }
+
+    fn restore(&mut self, snapshot: Vec<u32>) {
+        self.0 = snapshot;
+    }
+}
+
+type Server = Paxos<u32, MemStorage<Entry<u32>>, Log>;
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub servers: usize,
+    /// How many of the servers, from #1 on, are acceptors to begin with. They're
+    /// the ones clients know about.
+    pub members: usize,
+    /// Sets of acceptors clients ask for, at random times during the first second.
+    pub reconfigurations: Vec<BTreeSet<usize>>,
+    /// How many distinct values clients propose, at random times during the first second.
+    pub proposals: usize,
+    /// How many linearizable queries clients make, one per instance from 0 on, at
+    /// random times during the first three seconds.
+    pub queries: usize,
+    /// Every datagram takes up to this long to arrive, which also reorders them.
+    pub max_delay: Duration,
+    pub drop_rate: f64,
+    /// How many of the `Prepare`s and `Elect`s that get through are dropped as well,
+    /// so that acceptors get `Accept`s of rounds they never heard of.
+    pub phase1_drop_rate: f64,
+    pub dup_rate: f64,
+    /// How many datagrams are held up by as much as `late_delay` more, as if stuck
+    /// in a queue, to arrive long after what was sent with them.
+    pub late_rate: f64,
+    pub late_delay: Duration,
+    /// How long the cluster runs, in simulated time.
+    pub duration: Duration,
+    pub paxos: PaxosConfig,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            servers: 3,
+            members: 3,
+            reconfigurations: vec![],
+            proposals: 5,
+            queries: 0,
+            max_delay: Duration::from_millis(20),
+            drop_rate: 0.05,
+            phase1_drop_rate: 0.0,
+            dup_rate: 0.05,
+            late_rate: 0.0,
+            late_delay: Duration::from_secs(2),
+            duration: Duration::from_secs(10),
+            paxos: PaxosConfig::default(),
+        }
+    }
+}
+
+/// What a run that kept every invariant ended with.
+#[derive(Debug, PartialEq)]
+pub struct Report {
+    /// The value learned in each instance.
+    pub chosen: BTreeMap<usize, Entry<u32>>,
+    pub responses: Vec<Response<u32, usize>>,
+    /// How many times a server caught up through another's snapshot.
+    pub snapshots_installed: usize,
+    /// A digest of every delivery, in order. Two runs with the same seed must agree on it.
+    pub fingerprint: u64,
+}
+
+pub struct Simulation {
+    config: SimConfig,
+    rng: SmallRng,
+    start: Instant,
+    now: Instant,
+    servers: BTreeMap<usize, Server>,
+    // Datagrams in flight to the given server, by arrival time. The counter keeps
+    // datagrams arriving at the same time in sending order.
+    in_flight: BTreeMap<(Instant, u64), (usize, Incoming<u32, usize>)>,
+    sent: u64,
+    chosen: BTreeMap<usize, Entry<u32>>,
+    // When each instance was chosen, and when it was queried.
+    chosen_at: BTreeMap<usize, Instant>,
+    queried_at: BTreeMap<usize, Instant>,
+    // How far each server has applied the log.
+    applied_upto: BTreeMap<usize, usize>,
+    snapshots_installed: usize,
+    responses: Vec<Response<u32, usize>>,
+    fingerprint: DefaultHasher,
+}
+
+impl Simulation {
+    pub fn new(seed: u64, config: SimConfig) -> Self {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let start = Instant::now();
+        let peers_id: HashSet<usize> = (1..=config.members).collect();
+        let servers = (1..=config.servers)
+            .map(|id| {
+                let paxos_config = PaxosConfig {
+                    seed: Some(rng.gen()),
+                    ..config.paxos.clone()
+                };
+                let storage = MemStorage::new();
+                let server = Paxos::with_config(
+                    id,
+                    peers_id.clone(),
+                    storage,
+                    Log::default(),
+                    paxos_config,
+                    start,
+                );
+                (id, server)
+            })
+            .collect();
+        Self {
+            config,
+            rng,
+            start,
+            now: start,
+            servers,
+            in_flight: BTreeMap::new(),
+            sent: 0,
+            chosen: BTreeMap::new(),
+            chosen_at: BTreeMap::new(),
+            queried_at: BTreeMap::new(),
+            applied_upto: BTreeMap::new(),
+            snapshots_installed: 0,
+            responses: vec![],
+            fingerprint: DefaultHasher::new(),
+        }
+    }
+
+    /// Run the cluster to the end, or until an invariant breaks, which is
+    /// described in the error.
+    pub fn run(mut self) -> Result<Report, String> {
+        let mut calls = (CLIENT..).map(|client| RequestId { client, seq: 0 });
+        for value in 0..self.config.proposals as u32 {
+            let at = self.start + Duration::from_secs(1).mul_f64(self.rng.gen());
+            let dst = self.rng.gen_range(1, self.config.members + 1);
+            let id = calls.next().unwrap();
+            let dgram = Datagram::Request(Request::Propose { id, value });
+            self.schedule(
+                at,
+                dst,
+                Incoming {
+                    src: id.client,
+                    dgram,
+                },
+            );
+        }
+        for members in self.config.reconfigurations.clone() {
+            let at = self.start + Duration::from_secs(1).mul_f64(self.rng.gen());
+            let dst = self.rng.gen_range(1, self.config.members + 1);
+            let id = calls.next().unwrap();
+            let dgram = Datagram::Request(Request::Reconfigure { id, members });
+            self.schedule(
+                at,
+                dst,
+                Incoming {
+                    src: id.client,
+                    dgram,
+                },
+            );
+        }
+        for instance in 0..self.config.queries {
+            let at = self.start + Duration::from_secs(3).mul_f64(self.rng.gen());
+            let dst = self.rng.gen_range(1, self.config.members + 1);
+            let consistency = if self.rng.gen() {
+                ReadConsistency::Lease
+            } else {
+                ReadConsistency::Quorum
+            };
+            let id = calls.next().unwrap();
+            let req = Request::Query {
+                id,
+                instance,
+                consistency,
+            };
+            self.queried_at.insert(instance, at);
+            let dgram = Datagram::Request(req);
+            self.schedule(
+                at,
+                dst,
+                Incoming {
+                    src: id.client,
+                    dgram,
+                },
+            );
+        }
+
+        let end = self.start + self.config.duration;
+        let mut next_tick = self.start;
+        while next_tick <= end {
+            match self.in_flight.keys().next() {
+                Some(&(at, _)) if at <= next_tick => {
+                    let ((at, _), (dst, incoming)) = self.in_flight.pop_first().unwrap();
+                    self.now = at;
+                    self.deliver(dst, incoming)?;
+                }
+                _ => {
+                    self.now = next_tick;
+                    let ids: Vec<usize> = self.servers.keys().copied().collect();
+                    for id in ids {
+                        let effects = self.servers.get_mut(&id).unwrap().tick(self.now);
+                        self.process(id, effects)?;
+                    }
+                    next_tick += TICK_INTERVAL;
+                }
+            }
+        }
+        self.check_state_machines()?;
+        Ok(Report {
+            chosen: self.chosen,
+            responses: self.responses,
+            snapshots_installed: self.snapshots_installed,
+            fingerprint: self.fingerprint.finish(),
+        })
+    }
+
+    fn schedule(&mut self, at: Instant, dst: usize, incoming: Incoming<u32, usize>) {
+        self.in_flight.insert((at, self.sent), (dst, incoming));
+        self.sent += 1;
+    }
+
+    fn deliver(&mut self, dst: usize, incoming: Incoming<u32, usize>) -> Result<(), String> {
+        let elapsed = self.now - self.start;
+        self.fingerprint.write_u128(elapsed.as_nanos());
+        self.fingerprint.write_usize(dst);
+        self.fingerprint.write_usize(incoming.src);
+        self.fingerprint
+            .write(format!("{:?}", incoming.dgram).as_bytes());
+        if dst >= CLIENT {
+            if let Datagram::Response(resp) = incoming.dgram {
+                self.check_query(&resp)?;
+                self.responses.push(resp);
+            }
+            return Ok(());
+        }
+        let server = self.servers.get_mut(&dst).unwrap();
+        let effects = server.handle_incoming(incoming, self.now);
+        self.process(dst, effects)
+    }
+
+    /// A linearizable query must see whatever was chosen before it was made.
+    fn check_query(&self, resp: &Response<u32, usize>) -> Result<(), String> {
+        let (instance, val) = match resp {
+            Response::Query { instance, val, .. } => (*instance, val),
+            _ => return Ok(()),
+        };
+        let chosen = self.chosen.get(&instance);
+        let missed = match (
+            self.chosen_at.get(&instance),
+            self.queried_at.get(&instance),
+        ) {
+            (Some(chosen_at), Some(queried_at)) => chosen_at < queried_at,
+            _ => false,
+        };
+        if val.is_some() && val.as_ref() != chosen || val.is_none() && missed {
+            return Err(format!(
+                "Query of instance {} answered {:?}, where {:?} was chosen",
+                instance, val, chosen
+            ));
+        }
+        Ok(())
+    }
+
+    /// Every server must have applied the values chosen, in order and each once
+    /// however often it was chosen, however it got there: one at a time, or through
+    /// snapshots.
+    fn check_state_machines(&self) -> Result<(), String> {
+        let mut values = vec![];
+        let mut calls = HashSet::new();
+        for (expected, (&instance, entry)) in self.chosen.iter().enumerate() {
+            if instance != expected {
+                break;
+            }
+            let commands = match *entry {
+                Entry::Value { id, value } => vec![(id, value)],
+                Entry::Batch(ref commands) => commands.clone(),
+                _ => vec![],
+            };
+            for (id, value) in commands {
+                if calls.insert(id) {
+                    values.push(value);
+                }
+            }
+        }
+        for (id, server) in &self.servers {
+            let log = &server.state_machine().0;
+            if !values.starts_with(log) {
+                return Err(format!(
+                    "Server #{} applied {:?}, where {:?} was chosen",
+                    id, log, values
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check what server #`src` produced, and put its datagrams on the network.
+    fn process(&mut self, src: usize, effects: Effects<u32, usize>) -> Result<(), String> {
+        for Outgoing { dst, dgram } in effects.outgoing {
+            if let Datagram::Request(Request::Learn {
+                instance,
+                ref value,
+            }) = dgram
+            {
+                match self.chosen.get(&instance) {
+                    Some(chosen) if chosen != value => {
+                        return Err(format!(
+                            "Server #{} learns {:?} at instance {}, where {:?} was chosen",
+                            src, value, instance, chosen
+                        ));
+                    }
+                    Some(_) => {}
+                    None => {
+                        self.chosen.insert(instance, value.clone());
+                        self.chosen_at.insert(instance, self.now);
+                    }
+                }
+            }
+            let drop_rate = match dgram {
+                Datagram::Request(Request::Prepare { .. })
+                | Datagram::Request(Request::Elect { .. }) => {
+                    1.0 - (1.0 - self.config.drop_rate) * (1.0 - self.config.phase1_drop_rate)
+                }
+                _ => self.config.drop_rate,
+            };
+            let mut dst: Vec<usize> = dst.into_iter().collect();
+            dst.sort_unstable();
+            for id in dst {
+                if self.rng.gen_bool(drop_rate) {
+                    continue;
+                }
+                let copies = if self.rng.gen_bool(self.config.dup_rate) {
+                    2
+                } else {
+                    1
+                };
+                for _ in 0..copies {
+                    let mut at = self.now + self.config.max_delay.mul_f64(self.rng.gen());
+                    if self.config.late_rate > 0.0 && self.rng.gen_bool(self.config.late_rate) {
+                        at += self.config.late_delay.mul_f64(self.rng.gen());
+                    }
+                    let dgram = dgram.clone();
+                    self.schedule(at, id, Incoming { src, dgram });
+                }
+            }
+        }
+        for event in effects.events {
+            if let Event::SnapshotInstalled { upto } = event {
+                let applied_upto = self.applied_upto.entry(src).or_insert(0);
+                if upto <= *applied_upto {
+                    return Err(format!(
+                        "Server #{} installs a snapshot up to instance {}, behind its log",
+                        src, upto
+                    ));
+                }
+                *applied_upto = upto;
+                self.snapshots_installed += 1;
+            }
+            if let Event::Applied {
+                instance, value, ..
+            } = event
+            {
+                let upto = self.applied_upto.entry(src).or_insert(0);
+                // The commands of a batch are applied one after the other.
+                let batched = matches!(self.chosen.get(&instance), Some(Entry::Batch(_)));
+                if instance < *upto && !(batched && instance + 1 == *upto) {
+                    return Err(format!(
+                        "Server #{} applies instance {} again",
+                        src, instance
+                    ));
+                }
+                *upto = instance + 1;
+                let chosen = match self.chosen.get(&instance) {
+                    Some(Entry::Value { value: chosen, .. }) => *chosen == value,
+                    Some(Entry::Batch(commands)) => {
+                        commands.iter().any(|&(_, chosen)| chosen == value)
+                    }
+                    _ => false,
+                };
+                if !chosen {
+                    return Err(format!(
+                        "Server #{} applies {:?} at instance {}, where {:?} was chosen",
+                        src,
+                        value,
+                        instance,
+                        self.chosen.get(&instance)
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::quorum::{Flexible, Grid, QuorumSystem, Weighted};
+    use std::sync::Arc;
+
+    fn run(seed: u64, config: SimConfig) -> Report {
+        Simulation::new(seed, config)
+            .run()
+            .unwrap_or_else(|e| panic!("seed {}: {}", seed, e))
+    }
+
+    #[test]
+    fn test_agreement_across_seeds() {
+        for seed in 0..2000 {
+            run(seed, SimConfig::default());
+        }
+    }
+
+    #[test]
+    fn test_reliable_network_chooses_everything() {
+        let config = SimConfig {
+            drop_rate: 0.0,
+            dup_rate: 0.0,
+            ..SimConfig::default()
+        };
+        for seed in 0..20 {
+            let report = run(seed, config.clone());
+            let chosen = report
+                .responses
+                .iter()
+                .filter(|resp| {
+                    matches!(
+                        resp,
+                        Response::Propose {
+                            result: ProposeResult::Chosen { .. },
+                            ..
+                        }
+                    )
+                })
+                .count();
+            assert_eq!(chosen, config.proposals, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn test_agreement_across_reconfigurations() {
+        // Grow from three acceptors to five, then replace two of them.
+        let config = SimConfig {
+            servers: 6,
+            reconfigurations: vec![(1..=5).collect(), (2..=6).collect(), [1, 3, 6].into()],
+            ..SimConfig::default()
+        };
+        for seed in 0..300 {
+            run(seed, config.clone());
+        }
+        let reliable = SimConfig {
+            drop_rate: 0.0,
+            dup_rate: 0.0,
+            ..config
+        };
+        for seed in 0..20 {
+            let report = run(seed, reliable.clone());
+            let chosen = |resp: &&Response<u32, usize>| match resp {
+                Response::Propose { result, .. } => {
+                    matches!(result, ProposeResult::Chosen { .. })
+                }
+                Response::Reconfigure { result, .. } => {
+                    matches!(result, ProposeResult::Chosen { .. })
+                }
+                _ => false,
+            };
+            let count = report.responses.iter().filter(chosen).count();
+            assert_eq!(count, reliable.proposals + 3, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn test_agreement_with_flexible_quorums() {
+        let quorums: Vec<Arc<dyn QuorumSystem>> = vec![
+            Arc::new(Flexible { phase2: 2 }),
+            Arc::new(Grid { columns: 2 }),
+            Arc::new(Weighted {
+                weights: vec![(1, 2), (2, 2)].into_iter().collect(),
+            }),
+        ];
+        for quorums in quorums {
+            let config = SimConfig {
+                servers: 5,
+                members: 5,
+                paxos: PaxosConfig {
+                    quorums,
+                    ..PaxosConfig::default()
+                },
+                ..SimConfig::default()
+            };
+            for seed in 0..300 {
+                run(seed, config.clone());
+            }
+        }
+    }
+
+    #[test]
+    fn test_linearizable_queries() {
+        // Heavy losses keep leaders from hearing they've been replaced.
+        let lossy = SimConfig {
+            queries: 20,
+            proposals: 20,
+            drop_rate: 0.2,
+            ..SimConfig::default()
+        };
+        for seed in 0..500 {
+            run(seed, lossy.clone());
+        }
+        let config = SimConfig {
+            queries: 10,
+            ..SimConfig::default()
+        };
+        let mut answered = 0;
+        for seed in 0..100 {
+            let report = run(seed, config.clone());
+            answered += report
+                .responses
+                .iter()
+                .filter(|resp| matches!(resp, Response::Query { .. }))
+                .count();
+        }
+        // Queries fail until a leader is elected, about halfway through.
+        assert!(answered > 100 * config.queries / 3, "{} answered", answered);
+    }
+
+    #[test]
+    fn test_linearizable_queries_with_unpromised_accepts() {
+        // Acceptors that missed a new leader's election still accept its proposals,
+        // and must refuse the late `Accept`s of older rounds, and stop backing the
+        // old leader's reads, from then on. Leaders come and go all the time.
+        let config = SimConfig {
+            queries: 20,
+            proposals: 20,
+            max_delay: Duration::from_millis(100),
+            drop_rate: 0.1,
+            phase1_drop_rate: 0.5,
+            late_rate: 0.5,
+            late_delay: Duration::from_millis(300),
+            paxos: PaxosConfig {
+                election_timeout: Duration::from_millis(200),
+                lease_duration: Duration::from_millis(100),
+                ..PaxosConfig::default()
+            },
+            ..SimConfig::default()
+        };
+        for seed in 0..700 {
+            run(seed, config.clone());
+        }
+    }
+
+    #[test]
+    fn test_agreement_with_snapshots() {
+        // Lossy enough for servers to fall behind their peers' snapshots.
+        let config = SimConfig {
+            proposals: 30,
+            queries: 10,
+            drop_rate: 0.2,
+            paxos: PaxosConfig {
+                snapshot_interval: Some(4),
+                ..PaxosConfig::default()
+            },
+            ..SimConfig::default()
+        };
+        let mut installed = 0;
+        for seed in 0..300 {
+            installed += run(seed, config.clone()).snapshots_installed;
+        }
+        assert!(installed > 0);
+    }
+
+    #[test]
+    fn test_agreement_with_batching() {
+        let config = SimConfig {
+            paxos: PaxosConfig {
+                batch_window: Duration::from_millis(20),
+                max_batch: 4,
+                ..PaxosConfig::default()
+            },
+            ..SimConfig::default()
+        };
+        for seed in 0..300 {
+            run(seed, config.clone());
+        }
+        let reliable = SimConfig {
+            drop_rate: 0.0,
+            dup_rate: 0.0,
+            ..config
+        };
+        for seed in 0..20 {
+            let report = run(seed, reliable.clone());
+            let chosen = report
+                .responses
+                .iter()
+                .filter(|resp| {
+                    matches!(
+                        resp,
+                        Response::Propose {
+                            result: ProposeResult::Chosen { .. },
+                            ..
+                        }
+                    )
+                })
+                .count();
+            assert_eq!(chosen, reliable.proposals, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn test_replay_from_seed() {
+        let report = run(42, SimConfig::default());
+        assert_eq!(report, run(42, SimConfig::default()));
+        assert_ne!(
+            report.fingerprint,
+            run(43, SimConfig::default()).fingerprint
+        );
+        // Installing a snapshot answers the calls waiting below it, in a set order.
+        let config = SimConfig {
+            proposals: 30,
+            drop_rate: 0.2,
+            paxos: PaxosConfig {
+                snapshot_interval: Some(4),
+                ..PaxosConfig::default()
+            },
+            ..SimConfig::default()
+        };
+        for seed in 0..50 {
+            let report = run(seed, config.clone());
+            assert_eq!(report, run(seed, config.clone()));
+        }
+    }
+}
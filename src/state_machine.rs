@@ -0,0 +1,19 @@
+use crate::paxos::Value;
+
+/// The service a Paxos log replicates.
+///
+/// Every server applies the chosen commands to its own copy, one slot at a time and
+/// strictly in slot order, so all copies go through the same states. `apply` must
+/// therefore be deterministic: no clocks, randomness or I/O.
+pub trait StateMachine<V> {
+    /// What a command yields, sent back to the client that proposed it.
+    type Output: Value;
+    /// A copy of the state, which stands in for the commands applied so far when
+    /// the log is compacted, or when a server far behind catches up.
+    type Snapshot: Value;
+
+    fn apply(&mut self, cmd: V) -> Self::Output;
+    fn snapshot(&self) -> Self::Snapshot;
+    /// Replace the state with `snapshot`.
+    fn restore(&mut self, snapshot: Self::Snapshot);
+}
@@ -0,0 +1,359 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use crate::paxos::{AcceptedProposal, SequenceNumber, Snapshot, Value};
+use crate::wal::{Record, Wal};
+
+/// Where a server keeps the state Paxos requires to outlive a crash.
+///
+/// Setters must not return before the change is durable: the caller answers
+/// other servers right after they succeed.
+pub trait Storage<V> {
+    fn promised(&self, instance: usize) -> Option<SequenceNumber>;
+    fn accepted(&self, instance: usize) -> Option<AcceptedProposal<V>>;
+    fn chosen(&self, instance: usize) -> Option<V>;
+    /// The highest sequence number handed out by the local proposer.
+    fn current_seq(&self) -> Option<SequenceNumber>;
+    /// A promise covering every instance from the given one onwards, made to a leader candidate.
+    fn leader_promise(&self) -> Option<(usize, SequenceNumber)>;
+    /// The leader elected with this sequence number is the last we granted a lease to.
+    fn lease_holder(&self) -> Option<SequenceNumber>;
+    /// The highest instance we know anything about.
+    fn last_instance(&self) -> Option<usize>;
+    /// The latest snapshot, which replaces everything about the instances below its `upto`.
+    fn snapshot(&self) -> Option<&Snapshot>;
+
+    fn set_promised(&mut self, instance: usize, seq: SequenceNumber) -> io::Result<()>;
+    /// Accepting a proposal promises its sequence number as well, if that's higher than
+    /// the promise: a proposal accepted later mustn't be lower than this one.
+    fn set_accepted(&mut self, instance: usize, proposal: AcceptedProposal<V>) -> io::Result<()>;
+    fn set_chosen(&mut self, instance: usize, value: V) -> io::Result<()>;
+    fn set_current_seq(&mut self, seq: SequenceNumber) -> io::Result<()>;
+    fn set_leader_promise(&mut self, from: usize, seq: SequenceNumber) -> io::Result<()>;
+    fn set_lease_holder(&mut self, seq: SequenceNumber) -> io::Result<()>;
+    /// Keep `snapshot`, and discard the instances it covers.
+    fn set_snapshot(&mut self, snapshot: Snapshot) -> io::Result<()>;
+}
+
+/// Acceptor and learner state of a single slot in the replicated log.
+#[derive(Debug, Clone)]
+struct Instance<V> {
+    last_promised: Option<SequenceNumber>,
+    last_accepted_proposal: Option<AcceptedProposal<V>>,
+    chosen: Option<V>,
+}
+
+impl<V> Default for Instance<V> {
+    fn default() -> Self {
+        Self {
+            last_promised: None,
+            last_accepted_proposal: None,
+            chosen: None,
+        }
+    }
+}
+
+/// Volatile storage. Everything is lost with the process, so it's only suitable for tests.
+#[derive(Debug, Clone)]
+pub struct MemStorage<V> {
+    log: BTreeMap<usize, Instance<V>>,
+    current_seq: Option<SequenceNumber>,
+    leader_promise: Option<(usize, SequenceNumber)>,
+    lease_holder: Option<SequenceNumber>,
+    snapshot: Option<Snapshot>,
+}
+
+impl<V> MemStorage<V> {
+    pub fn new() -> Self {
+        Self {
+            log: BTreeMap::new(),
+            current_seq: None,
+            leader_promise: None,
+            lease_holder: None,
+            snapshot: None,
+        }
+    }
+
+    fn instance(&mut self, instance: usize) -> &mut Instance<V> {
+        self.log.entry(instance).or_default()
+    }
+}
+
+impl<V> Default for MemStorage<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V: Value> Storage<V> for MemStorage<V> {
+    fn promised(&self, instance: usize) -> Option<SequenceNumber> {
+        self.log.get(&instance)?.last_promised
+    }
+
+    fn accepted(&self, instance: usize) -> Option<AcceptedProposal<V>> {
+        self.log.get(&instance)?.last_accepted_proposal.clone()
+    }
+
+    fn chosen(&self, instance: usize) -> Option<V> {
+        self.log.get(&instance)?.chosen.clone()
+    }
+
+    fn current_seq(&self) -> Option<SequenceNumber> {
+        self.current_seq
+    }
+
+    fn leader_promise(&self) -> Option<(usize, SequenceNumber)> {
+        self.leader_promise
+    }
+
+    fn lease_holder(&self) -> Option<SequenceNumber> {
+        self.lease_holder
+    }
+
+    fn last_instance(&self) -> Option<usize> {
+        self.log.keys().next_back().copied()
+    }
+
+    fn snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+
+    fn set_promised(&mut self, instance: usize, seq: SequenceNumber) -> io::Result<()> {
+        self.instance(instance).last_promised = Some(seq);
+        Ok(())
+    }
+
+    fn set_accepted(&mut self, instance: usize, proposal: AcceptedProposal<V>) -> io::Result<()> {
+        let state = self.instance(instance);
+        state.last_promised = state.last_promised.max(Some(proposal.seq()));
+        state.last_accepted_proposal = Some(proposal);
+        Ok(())
+    }
+
+    fn set_chosen(&mut self, instance: usize, value: V) -> io::Result<()> {
+        self.instance(instance).chosen = Some(value);
+        Ok(())
+    }
+
+    fn set_current_seq(&mut self, seq: SequenceNumber) -> io::Result<()> {
+        self.current_seq = Some(seq);
+        Ok(())
+    }
+
+    fn set_leader_promise(&mut self, from: usize, seq: SequenceNumber) -> io::Result<()> {
+        self.leader_promise = Some((from, seq));
+        Ok(())
+    }
+
+    fn set_lease_holder(&mut self, seq: SequenceNumber) -> io::Result<()> {
+        self.lease_holder = Some(seq);
+        Ok(())
+    }
+
+    fn set_snapshot(&mut self, snapshot: Snapshot) -> io::Result<()> {
+        self.log = self.log.split_off(&snapshot.upto);
+        self.snapshot = Some(snapshot);
+        Ok(())
+    }
+}
+
+/// Storage backed by a write-ahead log on disk, with the whole state cached in memory.
+#[derive(Debug)]
+pub struct FileStorage<V> {
+    cache: MemStorage<V>,
+    wal: Wal,
+}
+
+impl<V: Value> FileStorage<V> {
+    /// Open the log at `path` and recover any state left there by a previous run.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let (wal, records) = Wal::open(path)?;
+        let mut cache = MemStorage::new();
+        for record in records {
+            match record {
+                Record::Promise { instance, seq } => cache.set_promised(instance, seq)?,
+                Record::Accept { instance, proposal } => cache.set_accepted(instance, proposal)?,
+                Record::Chosen { instance, value } => cache.set_chosen(instance, value)?,
+                Record::Seq(seq) => cache.set_current_seq(seq)?,
+                Record::LeaderPromise { from, seq } => cache.set_leader_promise(from, seq)?,
+                Record::Lease(seq) => cache.set_lease_holder(seq)?,
+                Record::Snapshot(snapshot) => cache.set_snapshot(snapshot)?,
+            }
+        }
+        Ok(Self { cache, wal })
+    }
+}
+
+impl<V: Value> Storage<V> for FileStorage<V> {
+    fn promised(&self, instance: usize) -> Option<SequenceNumber> {
+        self.cache.promised(instance)
+    }
+
+    fn accepted(&self, instance: usize) -> Option<AcceptedProposal<V>> {
+        self.cache.accepted(instance)
+    }
+
+    fn chosen(&self, instance: usize) -> Option<V> {
+        self.cache.chosen(instance)
+    }
+
+    fn current_seq(&self) -> Option<SequenceNumber> {
+        self.cache.current_seq()
+    }
+
+    fn leader_promise(&self) -> Option<(usize, SequenceNumber)> {
+        self.cache.leader_promise()
+    }
+
+    fn lease_holder(&self) -> Option<SequenceNumber> {
+        self.cache.lease_holder()
+    }
+
+    fn last_instance(&self) -> Option<usize> {
+        self.cache.last_instance()
+    }
+
+    fn snapshot(&self) -> Option<&Snapshot> {
+        self.cache.snapshot()
+    }
+
+    fn set_promised(&mut self, instance: usize, seq: SequenceNumber) -> io::Result<()> {
+        self.wal.append::<V>(&Record::Promise { instance, seq })?;
+        self.cache.set_promised(instance, seq)
+    }
+
+    fn set_accepted(&mut self, instance: usize, proposal: AcceptedProposal<V>) -> io::Result<()> {
+        self.wal.append(&Record::Accept {
+            instance,
+            proposal: proposal.clone(),
+        })?;
+        self.cache.set_accepted(instance, proposal)
+    }
+
+    fn set_chosen(&mut self, instance: usize, value: V) -> io::Result<()> {
+        self.wal.append(&Record::Chosen {
+            instance,
+            value: value.clone(),
+        })?;
+        self.cache.set_chosen(instance, value)
+    }
+
+    fn set_current_seq(&mut self, seq: SequenceNumber) -> io::Result<()> {
+        self.wal.append::<V>(&Record::Seq(seq))?;
+        self.cache.set_current_seq(seq)
+    }
+
+    fn set_leader_promise(&mut self, from: usize, seq: SequenceNumber) -> io::Result<()> {
+        self.wal.append::<V>(&Record::LeaderPromise { from, seq })?;
+        self.cache.set_leader_promise(from, seq)
+    }
+
+    fn set_lease_holder(&mut self, seq: SequenceNumber) -> io::Result<()> {
+        self.wal.append::<V>(&Record::Lease(seq))?;
+        self.cache.set_lease_holder(seq)
+    }
+
+    /// Rewrite the log with the snapshot, followed by what's left of the state.
+    fn set_snapshot(&mut self, snapshot: Snapshot) -> io::Result<()> {
+        let mut cache = self.cache.clone();
+        cache.set_snapshot(snapshot.clone())?;
+        let mut records = vec![Record::Snapshot(snapshot)];
+        for (&instance, state) in &cache.log {
+            if let Some(seq) = state.last_promised {
+                records.push(Record::Promise { instance, seq });
+            }
+            if let Some(proposal) = state.last_accepted_proposal.clone() {
+                records.push(Record::Accept { instance, proposal });
+            }
+            if let Some(value) = state.chosen.clone() {
+                records.push(Record::Chosen { instance, value });
+            }
+        }
+        records.extend(cache.current_seq.map(Record::Seq));
+        if let Some((from, seq)) = cache.leader_promise {
+            records.push(Record::LeaderPromise { from, seq });
+        }
+        records.extend(cache.lease_holder.map(Record::Lease));
+        self.wal.rewrite(&records)?;
+        self.cache = cache;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_file_storage_reopen() {
+        let path = std::env::temp_dir().join(format!("paxos-storage-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let seq = SequenceNumber::new(2, 3);
+        let high = SequenceNumber::new(3, 4);
+        {
+            let mut storage = FileStorage::<u32>::open(&path).unwrap();
+            storage.set_promised(1, seq).unwrap();
+            storage
+                .set_accepted(1, AcceptedProposal::new(seq, 42))
+                .unwrap();
+            // Accepted without a promise: the accept record stands for one.
+            storage
+                .set_accepted(2, AcceptedProposal::new(high, 5))
+                .unwrap();
+            storage.set_chosen(0, 7).unwrap();
+            storage.set_current_seq(SequenceNumber::new(1, 9)).unwrap();
+            storage.set_leader_promise(2, seq).unwrap();
+            storage.set_lease_holder(high).unwrap();
+        }
+
+        let storage = FileStorage::<u32>::open(&path).unwrap();
+        assert_eq!(storage.promised(1), Some(seq));
+        assert_eq!(storage.accepted(1), Some(AcceptedProposal::new(seq, 42)));
+        assert_eq!(storage.promised(2), Some(high));
+        assert_eq!(storage.chosen(0), Some(7));
+        assert_eq!(storage.chosen(1), None);
+        assert_eq!(storage.current_seq(), Some(SequenceNumber::new(1, 9)));
+        assert_eq!(storage.leader_promise(), Some((2, seq)));
+        assert_eq!(storage.lease_holder(), Some(high));
+        assert_eq!(storage.last_instance(), Some(2));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_storage_compaction() {
+        let path = std::env::temp_dir().join(format!("paxos-compact-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let seq = SequenceNumber::new(2, 3);
+        let snapshot = Snapshot {
+            upto: 2,
+            configs: vec![(0, (1..4).collect())].into_iter().collect(),
+            state: vec![1, 2, 3],
+            sessions: vec![],
+        };
+        {
+            let mut storage = FileStorage::<u32>::open(&path).unwrap();
+            for instance in 0..3 {
+                storage.set_chosen(instance, instance as u32).unwrap();
+            }
+            storage
+                .set_accepted(3, AcceptedProposal::new(seq, 42))
+                .unwrap();
+            storage.set_current_seq(SequenceNumber::new(1, 9)).unwrap();
+            storage.set_snapshot(snapshot.clone()).unwrap();
+            assert_eq!(storage.chosen(1), None);
+            storage.set_promised(4, seq).unwrap();
+        }
+
+        let storage = FileStorage::<u32>::open(&path).unwrap();
+        assert_eq!(storage.snapshot(), Some(&snapshot));
+        assert_eq!(storage.chosen(1), None);
+        assert_eq!(storage.chosen(2), Some(2));
+        assert_eq!(storage.accepted(3), Some(AcceptedProposal::new(seq, 42)));
+        assert_eq!(storage.promised(4), Some(seq));
+        assert_eq!(storage.current_seq(), Some(SequenceNumber::new(1, 9)));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
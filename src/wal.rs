@@ -0,0 +1,229 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::paxos::{AcceptedProposal, SequenceNumber, Snapshot, Value};
+
+/// A state change that must survive a crash before it's made visible to others.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Record<V> {
+    Promise {
+        instance: usize,
+        seq: SequenceNumber,
+    },
+    Accept {
+        instance: usize,
+        proposal: AcceptedProposal<V>,
+    },
+    Chosen {
+        instance: usize,
+        value: V,
+    },
+    /// The highest sequence number this server has handed out as a proposer.
+    Seq(SequenceNumber),
+    /// A promise for every instance from `from` onwards.
+    LeaderPromise {
+        from: usize,
+        seq: SequenceNumber,
+    },
+    /// A lease granted to the leader elected with this sequence number.
+    Lease(SequenceNumber),
+    /// Replaces every record about the instances it covers.
+    Snapshot(Snapshot),
+}
+
+const LEN: usize = std::mem::size_of::<u64>();
+const CRC: usize = std::mem::size_of::<u32>();
+const HEADER: usize = LEN + CRC;
+
+/// An append-only, fsynced log of `Record`s.
+///
+/// Each record is stored as a big-endian `u64` length, a big-endian CRC-32 of that
+/// length and the data, and then the data: the record's bincode encoding.
+#[derive(Debug)]
+pub struct Wal {
+    file: File,
+    path: PathBuf,
+}
+
+impl Wal {
+    /// Open the log at `path`, creating it if missing, and return every record
+    /// written so far. A torn record left by a crash in the middle of `append` is
+    /// dropped from the tail of the file; a damaged record anywhere else is an error,
+    /// as the records after it can't be trusted to follow it.
+    pub fn open<V: Value>(path: impl AsRef<Path>) -> io::Result<(Self, Vec<Record<V>>)> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut content = vec![];
+        file.read_to_end(&mut content)?;
+
+        let mut records = vec![];
+        let mut pos = 0;
+        while let Some((record, len)) = Self::decode(&content[pos..]).map_err(|e| {
+            let what = format!("{}: record at byte {}: {}", path.display(), pos, e);
+            io::Error::new(e.kind(), what)
+        })? {
+            records.push(record);
+            pos += len;
+        }
+        if pos != content.len() {
+            file.set_len(pos as u64)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(pos as u64))?;
+        Ok((Self { file, path }, records))
+    }
+
+    /// The first record in `buf` and its encoded size, or `None` if `buf` ends
+    /// before a whole record does.
+    fn decode<V: Value>(buf: &[u8]) -> io::Result<Option<(Record<V>, usize)>> {
+        if buf.len() < HEADER {
+            return Ok(None);
+        }
+        let mut len = [0u8; LEN];
+        len.copy_from_slice(&buf[..LEN]);
+        let mut crc = [0u8; CRC];
+        crc.copy_from_slice(&buf[LEN..HEADER]);
+        let end = match HEADER.checked_add(u64::from_be_bytes(len) as usize) {
+            Some(end) if end <= buf.len() => end,
+            _ => return Ok(None),
+        };
+        if checksum(&len, &buf[HEADER..end]) != u32::from_be_bytes(crc) {
+            // Only the last write may have been cut short.
+            if end == buf.len() {
+                return Ok(None);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "checksum mismatch",
+            ));
+        }
+        let record = bincode::deserialize(&buf[HEADER..end])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Some((record, end)))
+    }
+
+    fn encode<V: Value>(record: &Record<V>) -> io::Result<Vec<u8>> {
+        let data = bincode::serialize(record).map_err(io::Error::other)?;
+        let len = (data.len() as u64).to_be_bytes();
+        let mut buf = Vec::with_capacity(HEADER + data.len());
+        buf.extend_from_slice(&len);
+        buf.extend_from_slice(&checksum(&len, &data).to_be_bytes());
+        buf.extend_from_slice(&data);
+        Ok(buf)
+    }
+
+    /// Append `record` and wait until it reaches the disk.
+    pub fn append<V: Value>(&mut self, record: &Record<V>) -> io::Result<()> {
+        self.file.write_all(&Self::encode(record)?)?;
+        self.file.sync_data()
+    }
+
+    /// Replace the whole log with `records`. A crash leaves either the old log or
+    /// the new one.
+    pub fn rewrite<V: Value>(&mut self, records: &[Record<V>]) -> io::Result<()> {
+        let tmp = self.path.with_extension("compacting");
+        let mut file = File::create(&tmp)?;
+        for record in records {
+            file.write_all(&Self::encode(record)?)?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        // The rename itself has to reach the disk too.
+        if let Some(dir) = self.path.parent().filter(|dir| dir.exists()) {
+            File::open(dir)?.sync_all()?;
+        }
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// The CRC-32 of a record's encoded length and data, so that a damaged length is
+/// caught as well.
+fn checksum(len: &[u8], data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(len);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_recover_and_drop_torn_tail() {
+        let path = std::env::temp_dir().join(format!("paxos-wal-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let promise = Record::Promise {
+            instance: 3,
+            seq: SequenceNumber::new(1, 2),
+        };
+        let chosen = Record::Chosen {
+            instance: 3,
+            value: 42,
+        };
+        {
+            let (mut wal, records) = Wal::open::<u32>(&path).unwrap();
+            assert!(records.is_empty());
+            wal.append(&promise).unwrap();
+            wal.append(&chosen).unwrap();
+        }
+        // Simulate a crash in the middle of writing a record.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 0, 0, 0, 0, 9, 1, 2]).unwrap();
+        drop(file);
+
+        let (mut wal, records) = Wal::open(&path).unwrap();
+        assert_eq!(records, vec![promise.clone(), chosen.clone()]);
+        let seq = Record::Seq(SequenceNumber::new(1, 7));
+        wal.append(&seq).unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(&path).unwrap();
+        assert_eq!(records, vec![promise, chosen, seq]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_damaged_records() {
+        let path = std::env::temp_dir().join(format!("paxos-wal-damage-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let seqs: Vec<Record<u32>> = (1..4)
+            .map(|n| Record::Seq(SequenceNumber::new(1, n)))
+            .collect();
+        {
+            let (mut wal, _) = Wal::open::<u32>(&path).unwrap();
+            for seq in &seqs {
+                wal.append(seq).unwrap();
+            }
+        }
+        let content = std::fs::read(&path).unwrap();
+        let size = content.len() / seqs.len();
+
+        // A write of the last record that reached the disk only in part.
+        let mut torn = content.clone();
+        torn[content.len() - 1] ^= 1;
+        std::fs::write(&path, &torn).unwrap();
+        let (_, records) = Wal::open(&path).unwrap();
+        assert_eq!(records, seqs[..2].to_vec());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 2 * size as u64);
+
+        // Anywhere else, it's the disk losing data we said was durable.
+        let mut damaged = content;
+        damaged[size + HEADER] ^= 1;
+        std::fs::write(&path, &damaged).unwrap();
+        let err = Wal::open::<u32>(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&path).unwrap(), damaged);
+        std::fs::remove_file(&path).unwrap();
+    }
+}